@@ -12,3 +12,55 @@ REGION_ALIAS("REGION_STACK", RAM);
 
 _max_hart_id = 4;
 _heap_size = 0xf424000;
+
+/* Machine mode code and data. Kept in its own page aligned section so that
+   PMP can hide it from S-mode. */
+SECTIONS
+{
+  .machine : ALIGN(4096)
+  {
+    _smachine = .;
+    *(.machine.text .machine.text.*);
+    *(.machine.data .machine.data.*);
+    . = ALIGN(4096);
+    _emachine = .;
+  } > RAM
+}
+INSERT AFTER .text;
+
+/* Relocations of the position independent image, applied at boot when the
+   kernel is moved by KASLR. */
+SECTIONS
+{
+  .rela.dyn : ALIGN(8)
+  {
+    __rela_dyn_start = .;
+    *(.rela .rela.*);
+    __rela_dyn_end = .;
+  } > RAM
+}
+INSERT AFTER .rodata;
+
+/* Symbol table for backtraces, filled in after linking by scripts/ksyms.sh */
+SECTIONS
+{
+  .ksyms : ALIGN(8)
+  {
+    __ksyms_start = .;
+    KEEP(*(.ksyms));
+    __ksyms_end = .;
+  } > RAM
+}
+INSERT AFTER .rodata;
+
+/* Exception table, see src/extable.rs */
+SECTIONS
+{
+  __ex_table : ALIGN(8)
+  {
+    __start___ex_table = .;
+    KEEP(*(__ex_table));
+    __stop___ex_table = .;
+  } > RAM
+}
+INSERT AFTER .rodata;
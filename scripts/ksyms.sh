@@ -0,0 +1,32 @@
+#!/bin/sh
+# Embed the symbol table of a kernel into its .ksyms section
+#
+# The table is the text symbols as listed by nm, sorted by address, one
+# "address type name" line each. It is padded with NULs to the size of the
+# section, which the kernel reserves in src/ksyms.rs.
+set -e
+
+elf="$1"
+NM="${NM:-llvm-nm}"
+OBJCOPY="${OBJCOPY:-llvm-objcopy}"
+SIZE="${SIZE:-llvm-size}"
+
+size=$($SIZE -A "$elf" | awk '$1 == ".ksyms" { print $2 }')
+if [ -z "$size" ]; then
+    echo "ksyms: $elf has no .ksyms section" >&2
+    exit 1
+fi
+
+table=$(mktemp)
+trap 'rm -f "$table"' EXIT
+
+$NM --defined-only --numeric-sort --demangle "$elf" |
+    awk '$2 ~ /^[tTwW]$/' >"$table"
+
+if [ "$(wc -c <"$table")" -ge "$size" ]; then
+    echo "ksyms: symbol table does not fit in $size bytes" >&2
+    exit 1
+fi
+
+truncate -s "$size" "$table"
+$OBJCOPY --update-section .ksyms="$table" "$elf"
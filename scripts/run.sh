@@ -0,0 +1,20 @@
+#!/bin/sh
+# Cargo runner: embed the symbol table, then boot the kernel in QEMU
+#
+# FBCON=1 adds a ramfb framebuffer and a window that shows it, for the
+# framebuffer console.
+set -e
+
+"$(dirname "$0")/ksyms.sh" "$1"
+
+display="-display none"
+if [ -n "$FBCON" ]; then
+    display="-device ramfb"
+fi
+
+exec qemu-system-riscv64 -machine virt -cpu rv64 -smp 4 -m 1024M \
+    $display -serial stdio -bios none \
+    -device virtio-rng-device -device virtio-gpu-device \
+    -device virtio-net-device -device virtio-tablet-device \
+    -device virtio-keyboard-device \
+    -kernel "$@"
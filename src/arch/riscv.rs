@@ -1,3 +1,4 @@
+use crate::clocksource;
 use crate::page::PAGE_SIZE;
 
 use core::arch::asm;
@@ -21,9 +22,9 @@ pub fn wait() {
     }
 }
 
+/// Time since mtime started
 pub fn uptime() -> Duration {
-    let time = time();
-    Duration::from_nanos(time as u64 * 100)
+    clocksource::uptime()
 }
 
 pub fn time() -> usize {
@@ -35,7 +36,7 @@ pub fn set_time(timer_val: usize) {
 
     unsafe {
         return core::ptr::write_volatile(
-            (offset::BASE + offset::MTIMECMP + hart_id) as *mut usize,
+            (offset::BASE + offset::MTIMECMP + 8 * hart_id) as *mut usize,
             timer_val,
         );
     }
@@ -52,8 +53,28 @@ pub fn thread_pointer() -> usize {
     hart_id
 }
 
+/// Make stores to instruction memory visible to the fetches of this hart
+pub fn fence_i() {
+    unsafe { asm!("fence.i") }
+}
+
 pub unsafe fn clear_sie_ssoft() {
     const SSIP: usize = 1 << 1;
 
     asm!("csrc sip, {}", in(reg) SSIP);
 }
+
+/// Run `f` with supervisor interrupts disabled
+///
+/// Interrupts are enabled again afterwards if they were enabled before.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = intr_get();
+    if enabled {
+        unsafe { riscv::register::sstatus::clear_sie() };
+    }
+    let ret = f();
+    if enabled {
+        unsafe { riscv::register::sstatus::set_sie() };
+    }
+    ret
+}
@@ -0,0 +1,96 @@
+// Backtraces by walking the frame pointers
+//
+// The kernel is built with frame pointers. The prologue of every function
+// stores the return address at fp - 8 and the frame pointer of its caller
+// at fp - 16, so the frames form a linked list through the stack.
+
+use crate::arch::riscv::thread_pointer;
+use crate::interrupt;
+use crate::ksyms;
+use crate::println;
+use crate::symbols::{KERNEL_STACK_END, KERNEL_STACK_START};
+
+use core::arch::asm;
+
+/// Frames printed at most, in case the chain loops
+const MAX_FRAMES: usize = 32;
+
+/// Iterator over the return addresses on the stack
+pub struct Frames {
+    fp: usize,
+    depth: usize,
+}
+
+impl Frames {
+    /// Walk the frames starting at the frame pointer `fp`
+    pub fn new(fp: usize) -> Self {
+        Self { fp, depth: 0 }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.depth >= MAX_FRAMES || !is_frame(self.fp) {
+            return None;
+        }
+
+        let (ra, fp) = unsafe {
+            let record = (self.fp - 16) as *const usize;
+            (record.add(1).read(), record.read())
+        };
+        self.fp = fp;
+        self.depth += 1;
+        (ra != 0).then_some(ra)
+    }
+}
+
+/// Check if `fp` can be the frame pointer of a frame on a kernel stack
+fn is_frame(fp: usize) -> bool {
+    let on_stack = |addr: usize| {
+        (KERNEL_STACK_END()..=KERNEL_STACK_START()).contains(&addr) || interrupt::on_irq_stack(addr)
+    };
+    fp % 8 == 0 && fp >= 16 && on_stack(fp - 16) && on_stack(fp - 1)
+}
+
+fn print_frame(index: usize, addr: usize, return_address: bool) {
+    // A return address may be just past the end of a function that never
+    // returns, look up the call instead
+    let lookup = if return_address { addr - 1 } else { addr };
+    match ksyms::lookup(lookup) {
+        Some((name, offset)) => {
+            let offset = offset + (addr - lookup);
+            println!(" #{:<2} 0x{:016x} {}+0x{:x}", index, addr, name, offset)
+        }
+        None => println!(" #{:<2} 0x{:016x}", index, addr),
+    }
+}
+
+/// Print the backtrace of the current hart
+#[inline(never)]
+pub fn backtrace() {
+    let fp: usize;
+    unsafe { asm!("mv {}, s0", out(reg) fp) };
+
+    println!("Backtrace of hart {}:", thread_pointer());
+    for (index, ra) in Frames::new(fp).enumerate() {
+        print_frame(index, ra, true);
+    }
+}
+
+/// Print the backtrace of code interrupted at `pc` with frame pointer `fp`
+pub fn backtrace_from(pc: usize, fp: usize) {
+    backtrace_of(thread_pointer(), pc, fp);
+}
+
+/// Print the backtrace of `hart`, stopped at `pc` with frame pointer `fp`
+///
+/// The hart must stay where it is while its stack is walked.
+pub fn backtrace_of(hart: usize, pc: usize, fp: usize) {
+    println!("Backtrace of hart {}:", hart);
+    print_frame(0, pc, false);
+    for (index, ra) in Frames::new(fp).enumerate() {
+        print_frame(index + 1, ra, true);
+    }
+}
@@ -1,5 +1,10 @@
 use crate::arch;
+use crate::hart::MAX_HARTS;
+use crate::symbols::symbol;
+use crate::watchdog;
+use core::arch::asm;
 use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use riscv::register::*;
 
@@ -9,12 +14,19 @@ pub const CLINT_BASE: usize = 0x2_000_000;
 pub const CLINT_SIZE: usize = 0x10_000;
 pub const CLINT_MTIME_OFFSET: usize = 0xBFF8;
 pub const CLINT_MTIMECMP_OFFSET: usize = 0x4_000;
+pub const CLINT_MSIP_OFFSET: usize = 0x0;
 
-const TIMER_INTERVAL: u64 = 1_000_000;
+/// Scratch area for `timervec`, only accessible from machine mode
+#[link_section = ".machine.data"]
+static mut TIMER_SCRATCH: [[u64; 7]; MAX_HARTS] = [[0u64; 7]; MAX_HARTS];
 
-const MAX_HARTS: usize = 8;
+const NO_TICK: AtomicUsize = AtomicUsize::new(0);
 
-static mut TIMER_SCRATCH: [[u64; 5]; MAX_HARTS] = [[0u64; 5]; MAX_HARTS];
+/// Set by `timervec` when it forwards a timer interrupt
+///
+/// Timer ticks and IPIs both reach S-mode as a software interrupt, the
+/// flag tells them apart.
+static TICK_PENDING: [AtomicUsize; MAX_HARTS] = [NO_TICK; MAX_HARTS];
 
 unsafe fn read_mtime() -> u64 {
     ptr::read_volatile((CLINT_BASE + CLINT_MTIME_OFFSET) as *const u64)
@@ -29,48 +41,84 @@ unsafe fn write_mtimecmp(hart: usize, val: u64) {
     ptr::write_volatile(addr, val);
 }
 
-unsafe fn increment_mtimecmp(hart: usize, interval: u64) {
-    let current = read_mtime();
-    write_mtimecmp(hart, current + interval);
+const fn mtimecmp(hart: usize) -> usize {
+    CLINT_BASE + 8 * hart + CLINT_MTIMECMP_OFFSET
 }
 
-fn mtiecmp_hart() -> usize {
-    let hart = arch::riscv::thread_pointer();
-    CLINT_BASE + 8 * hart + CLINT_MTIMECMP_OFFSET
+const fn msip(hart: usize) -> usize {
+    CLINT_BASE + 4 * hart + CLINT_MSIP_OFFSET
+}
+
+/// Setup machine mode trap handling for the current hart
+///
+/// Machine software interrupts are enabled so that other harts can reach
+/// this one through its msip register, see `send_soft`. The timer interrupt
+/// is enabled too, with no event programmed.
+///
+/// # Safety
+///
+/// Must be called in machine mode, once per hart.
+pub unsafe fn hartinit() {
+    let hart = mhartid::read();
+    TIMER_SCRATCH[hart][3] = mtimecmp(hart) as u64;
+    TIMER_SCRATCH[hart][4] = watchdog::snapshot_addr(hart) as u64;
+    TIMER_SCRATCH[hart][5] = msip(hart) as u64;
+    TIMER_SCRATCH[hart][6] = &TICK_PENDING[hart] as *const AtomicUsize as u64;
+
+    mscratch::write(TIMER_SCRATCH[hart].as_ptr() as usize);
+    mtvec::write(symbol!("timervec"), stvec::TrapMode::Direct);
+
+    write_mtimecmp(hart, u64::MAX);
+    mie::set_msoft();
+    mie::set_mtimer();
 }
 
 pub fn timer_init() {
     info!("Enabling timer interrupts");
-    // Enable machine mode timer interrupts
+    // Enable machine mode interrupts
     unsafe {
-        extern "C" {
-            fn timervec();
-        }
-
-        let hart = arch::riscv::thread_pointer();
-        increment_mtimecmp(hart, TIMER_INTERVAL);
-
-        TIMER_SCRATCH[hart][3] = mtiecmp_hart() as u64;
-        TIMER_SCRATCH[hart][4] = TIMER_INTERVAL;
+        mstatus::set_mie();
+    }
+}
 
-        mscratch::write(TIMER_SCRATCH[hart].as_ptr() as usize);
+/// Raise a timer interrupt on the current hart once mtime reaches
+/// `deadline`, replacing the previous event
+///
+/// `timervec` clears the event when it fires, so that it fires once.
+pub fn set_next_event(deadline: u64) {
+    let hart = arch::riscv::thread_pointer();
+    unsafe {
+        write_mtimecmp(hart, deadline);
+    }
+}
 
-        mtvec::write(timervec as usize, stvec::TrapMode::Direct);
+/// Clear the timer event of a hart
+pub fn timer_stop(hart: usize) {
+    unsafe {
+        write_mtimecmp(hart, u64::MAX);
+    }
+}
 
-        mstatus::set_mie();
-        mie::set_mtimer();
+/// Raise a machine software interrupt on a hart
+///
+/// Machine mode forwards it to S-mode as a software interrupt.
+pub fn send_soft(hart: usize) {
+    unsafe {
+        // Make earlier memory writes visible before the interrupt
+        asm!("fence w, o");
+        ptr::write_volatile(msip(hart) as *mut u32, 1);
     }
 }
 
+/// Check and clear the tick flag of the current hart
+pub fn take_tick() -> bool {
+    let hart = arch::riscv::thread_pointer();
+    TICK_PENDING[hart].swap(0, Ordering::AcqRel) != 0
+}
+
 pub fn debug() {
     let hart = arch::riscv::thread_pointer();
     unsafe {
-        info!(
-            "{} {} 0x{:X} {}",
-            read_mtime(),
-            read_mtimecmp(hart),
-            TIMER_SCRATCH[hart][3],
-            TIMER_SCRATCH[hart][4]
-        );
+        info!("{} {}", read_mtime(), read_mtimecmp(hart));
     }
 }
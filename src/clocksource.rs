@@ -0,0 +1,199 @@
+// The clocksource, mtime at the timebase frequency
+//
+// mtime counts at the `timebase-frequency` of /cpus in the device tree, the
+// same on every hart. It only goes forward, so an `Instant` read on one
+// hart can be compared with one read on another. Conversions to and from
+// `Duration` go through 128 bits and do not overflow for any mtime value.
+
+use crate::arch::riscv::{self, intr_get, thread_pointer, wait};
+use crate::fdt;
+use crate::hart::MAX_HARTS;
+use crate::timer::{self, Timer};
+use crate::watchdog;
+
+use core::hint::spin_loop;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use log::{info, warn};
+
+/// Frequency of QEMU virt, used when the device tree has none
+const DEFAULT_FREQUENCY: u64 = 10_000_000;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// mtime ticks per second
+static FREQUENCY: AtomicU64 = AtomicU64::new(DEFAULT_FREQUENCY);
+
+/// Read the timebase frequency from the device tree
+///
+/// Runs before anything else reads the clock, can be called in machine mode.
+pub fn init() {
+    let frequency = fdt::fdt()
+        .and_then(|fdt| fdt.property("/cpus", "timebase-frequency"))
+        .and_then(|frequency| frequency.as_u64());
+
+    match frequency {
+        Some(frequency) if frequency > 0 => {
+            FREQUENCY.store(frequency, Ordering::Relaxed);
+            info!("clocksource: mtime at {} Hz", frequency);
+        }
+        _ => warn!(
+            "clocksource: no timebase-frequency, assuming {} Hz",
+            DEFAULT_FREQUENCY
+        ),
+    }
+}
+
+/// mtime ticks per second
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Convert mtime ticks to a duration, rounding down
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SEC / frequency() as u128;
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+/// Convert a duration to mtime ticks, rounding up so a wait is never short
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = (duration.as_nanos() * frequency() as u128 + NANOS_PER_SEC - 1) / NANOS_PER_SEC;
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// A point in time, in mtime ticks
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(riscv::time() as u64)
+    }
+
+    /// The instant `ticks` mtime ticks after mtime started
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    pub const fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    /// Time since `earlier`, zero if `earlier` is later
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(ticks_to_duration)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().saturating_duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration_to_ticks(duration)).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration_to_ticks(duration)).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    /// Saturates to zero, like `std::time::Instant`
+    fn sub(self, earlier: Instant) -> Duration {
+        self.saturating_duration_since(earlier)
+    }
+}
+
+/// Time since mtime started
+pub fn uptime() -> Duration {
+    ticks_to_duration(riscv::time() as u64)
+}
+
+/// Spin until `deadline` has passed
+fn spin_until(deadline: Instant) {
+    while Instant::now() < deadline {
+        spin_loop();
+    }
+}
+
+/// Busy wait for at least `us` microseconds
+///
+/// For short delays, safe in any context.
+pub fn udelay(us: u64) {
+    spin_until(Instant::now() + Duration::from_micros(us));
+}
+
+/// Busy wait for at least `ms` milliseconds
+pub fn mdelay(ms: u64) {
+    spin_until(Instant::now() + Duration::from_millis(ms));
+}
+
+fn wake(_: usize) {}
+
+const SLEEP_TIMER: Timer = Timer::new(wake, 0);
+
+/// Timer that ends the sleep of each hart
+static SLEEP: [Timer; MAX_HARTS] = [SLEEP_TIMER; MAX_HARTS];
+
+/// Sleep for at least `ms` milliseconds
+///
+/// The hart waits for interrupts until then, a timer wakes it up at the
+/// end. With interrupts disabled it busy waits instead. Must not be called
+/// from interrupt context.
+pub fn msleep(ms: u64) {
+    let deadline = Instant::now() + Duration::from_millis(ms);
+    if !intr_get() {
+        return spin_until(deadline);
+    }
+
+    let sleep = &SLEEP[thread_pointer()];
+    if timer::mod_timer(sleep, deadline.ticks() as usize).is_err() {
+        return spin_until(deadline);
+    }
+    while Instant::now() < deadline {
+        wait();
+        watchdog::touch();
+    }
+    timer::del_timer(sleep);
+}
@@ -0,0 +1,54 @@
+// Kernel command line
+//
+// Read from `/chosen/bootargs`, set with `-append` in QEMU. Options are
+// separated by spaces and are either flags, `nokaslr`, or `key=value`.
+
+use crate::fdt;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::info;
+
+/// Address and length of the command line, kept in the device tree
+static CMDLINE_ADDR: AtomicUsize = AtomicUsize::new(0);
+static CMDLINE_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// The whole command line, empty if none was given
+pub fn cmdline() -> &'static str {
+    let addr = CMDLINE_ADDR.load(Ordering::Acquire);
+    let len = CMDLINE_LEN.load(Ordering::Acquire);
+    if addr == 0 {
+        return "";
+    }
+    unsafe {
+        let bytes = core::slice::from_raw_parts(addr as *const u8, len);
+        core::str::from_utf8_unchecked(bytes)
+    }
+}
+
+/// Check if the flag `name` was given
+pub fn has(name: &str) -> bool {
+    cmdline().split_whitespace().any(|option| option == name)
+}
+
+/// Value of the option `key=value`
+pub fn get(key: &str) -> Option<&'static str> {
+    cmdline()
+        .split_whitespace()
+        .filter_map(|option| option.split_once('='))
+        .find(|&(k, _)| k == key)
+        .map(|(_, value)| value)
+}
+
+/// Read the command line from the device tree
+pub fn init() {
+    let bootargs = fdt::fdt()
+        .and_then(|fdt| fdt.property("/chosen", "bootargs"))
+        .and_then(|property| property.as_str());
+
+    if let Some(bootargs) = bootargs {
+        info!("Command line: {}", bootargs);
+        CMDLINE_LEN.store(bootargs.len(), Ordering::Release);
+        CMDLINE_ADDR.store(bootargs.as_ptr() as usize, Ordering::Release);
+    }
+}
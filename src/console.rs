@@ -0,0 +1,238 @@
+// The kernel console
+//
+// `print!` writes to every registered backend. One `print!`, so one log
+// record, is written under one lock, taken with interrupts disabled, so
+// records from different harts or from an interrupt never mix. A hart that
+// traps while it holds the lock and prints from the trap handler writes
+// without taking it again.
+//
+// A panicking hart gives the owner of the lock a moment to finish its
+// record, then takes the lock by force, for good, and makes every backend
+// drop the locks of its own. Its last words come out even if the owner
+// never lets go, and the output of the other harts is dropped from then on.
+
+use crate::arch::riscv::{thread_pointer, without_interrupts};
+use crate::clocksource::Instant;
+use crate::cmdline;
+use crate::memlog;
+use crate::sbi;
+use crate::uart;
+
+use core::fmt::{self, Write};
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use log::{info, warn};
+
+/// Number of backends that can be registered
+const MAX_BACKENDS: usize = 4;
+
+/// How long a panicking hart waits for the lock before it takes it
+const FORCE_TIMEOUT: Duration = Duration::from_millis(10);
+
+const NO_HART: usize = usize::MAX;
+
+#[macro_export]
+macro_rules! print {
+    ($($args:tt)+) => ({
+        $crate::console::print(format_args!($($args)+));
+    });
+}
+#[macro_export]
+macro_rules! println {
+    () => ({ $crate::print!("\r\n") });
+    ($fmt:expr) => ({ $crate::print!(concat!($fmt, "\r\n")) });
+    ($fmt:expr, $($args:tt)+) => ({ $crate::print!(concat!($fmt, "\r\n"), $($args)+) });
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Error {
+    /// All backend slots are taken
+    Full,
+    /// A backend with the same name is registered
+    Exists,
+    /// Another hart panicked and holds the console
+    Panicked,
+}
+
+/// Somewhere console output goes
+pub trait Backend: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Write `bytes`, called with the console locked and interrupts disabled
+    fn write(&self, bytes: &[u8]);
+
+    /// Wait until the output has gone out
+    fn flush(&self) {}
+
+    /// Drop the locks the backend holds, on panic
+    ///
+    /// # Safety
+    ///
+    /// The hart that held them may still be in the backend.
+    unsafe fn force_unlock(&self) {}
+}
+
+/// Hart that holds the console lock
+static OWNER: AtomicUsize = AtomicUsize::new(NO_HART);
+/// The first hart that panicked
+static PANIC_HART: AtomicUsize = AtomicUsize::new(NO_HART);
+
+/// Only accessed with the console locked
+static mut BACKENDS: [Option<&'static dyn Backend>; MAX_BACKENDS] =
+    [Some(&uart::CONSOLE), Some(&memlog::CONSOLE), None, None];
+
+/// Whether a hart other than `hart` panicked and took the console
+fn panicked(hart: usize) -> bool {
+    let panic_hart = PANIC_HART.load(Ordering::Acquire);
+    panic_hart != NO_HART && panic_hart != hart
+}
+
+/// Take the console lock, returns false if the current hart already held it
+///
+/// Returns None once another hart panicked, it never gives the lock back.
+fn lock(hart: usize) -> Option<bool> {
+    if OWNER.load(Ordering::Relaxed) == hart {
+        return Some(false);
+    }
+    while OWNER
+        .compare_exchange_weak(NO_HART, hart, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        if panicked(hart) {
+            return None;
+        }
+        spin_loop();
+    }
+    Some(true)
+}
+
+fn unlock(hart: usize) {
+    // Not if a panicking hart took it meanwhile
+    let _ = OWNER.compare_exchange(hart, NO_HART, Ordering::Release, Ordering::Relaxed);
+}
+
+/// Run `f` with the console locked and interrupts disabled
+///
+/// Returns None without running `f` once another hart panicked.
+fn locked<R>(f: impl FnOnce(&mut [Option<&'static dyn Backend>; MAX_BACKENDS]) -> R) -> Option<R> {
+    without_interrupts(|| {
+        let hart = thread_pointer();
+        let locked = lock(hart)?;
+        let ret = f(unsafe { &mut *ptr::addr_of_mut!(BACKENDS) });
+        if locked {
+            unlock(hart);
+        }
+        Some(ret)
+    })
+}
+
+/// Writes to every backend
+struct Writer<'a>(&'a [Option<&'static dyn Backend>]);
+
+impl Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for backend in self.0.iter().flatten() {
+            backend.write(s.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+/// Write a record to the console, see `print!`
+pub fn print(args: fmt::Arguments) {
+    locked(|backends| {
+        let _ = Writer(backends).write_fmt(args);
+    });
+}
+
+/// Add `backend`, it gets all output from now on
+pub fn register(backend: &'static dyn Backend) -> Result<(), Error> {
+    locked(|backends| {
+        if backends
+            .iter()
+            .flatten()
+            .any(|other| other.name() == backend.name())
+        {
+            return Err(Error::Exists);
+        }
+        let slot = backends
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(Error::Full)?;
+        *slot = Some(backend);
+        Ok(())
+    })
+    .unwrap_or(Err(Error::Panicked))?;
+    info!("console: {} enabled", backend.name());
+    Ok(())
+}
+
+/// Remove the backend called `name`, returns whether there was one
+pub fn unregister(name: &str) -> bool {
+    locked(|backends| {
+        let slot = backends
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |backend| backend.name() == name));
+        slot.map(|slot| slot.take()).is_some()
+    })
+    .unwrap_or(false)
+}
+
+/// Wait until all output has gone out
+pub fn flush() {
+    locked(|backends| {
+        for backend in backends.iter().flatten() {
+            backend.flush();
+        }
+    });
+}
+
+/// Take the console lock by force, for the panic handler
+///
+/// Only the first hart that panics takes it, it waits a moment for the
+/// owner to finish its record first. The lock stays with that hart.
+pub fn force_unlock() {
+    let hart = thread_pointer();
+    if PANIC_HART
+        .compare_exchange(NO_HART, hart, Ordering::AcqRel, Ordering::Relaxed)
+        .is_err()
+    {
+        return;
+    }
+
+    let deadline = Instant::now() + FORCE_TIMEOUT;
+    loop {
+        let owner = OWNER.load(Ordering::Relaxed);
+        if owner == NO_HART || owner == hart || Instant::now() >= deadline {
+            break;
+        }
+        spin_loop();
+    }
+
+    OWNER.store(hart, Ordering::Release);
+    unsafe {
+        for backend in (*ptr::addr_of!(BACKENDS)).iter().flatten() {
+            backend.force_unlock();
+        }
+    }
+}
+
+/// Choose the backends from the command line
+///
+/// With `console=sbi` output goes to the SBI debug console instead of the
+/// UART.
+pub fn init() {
+    if cmdline::get("console") != Some("sbi") {
+        return;
+    }
+    if !sbi::probe_extension(sbi::EXT_DBCN) {
+        warn!("console: no SBI debug console");
+        return;
+    }
+    if register(&sbi::CONSOLE).is_ok() {
+        unregister(uart::CONSOLE.name());
+    }
+}
@@ -0,0 +1,75 @@
+// Exception table
+//
+// Instructions that are allowed to fault, such as the loads and stores of
+// the user access functions, get an entry that points to code to continue
+// at instead. The entries are emitted from assembly with `ex_table!` and
+// collected in the __ex_table section. Offsets are relative to the entry,
+// which keeps the table free of relocations.
+
+use crate::symbols::{EX_TABLE_END, EX_TABLE_START};
+use crate::trap::TrapFrame;
+
+use core::mem::size_of;
+
+/// Emit an exception table entry, for use in assembly
+///
+/// Execution continues at label `$fixup` when the instruction at label
+/// `$insn` faults.
+#[macro_export]
+macro_rules! ex_table {
+    ($insn:literal, $fixup:literal) => {
+        concat!(
+            ".pushsection __ex_table, \"a\"\n",
+            ".balign 4\n",
+            ".word ",
+            $insn,
+            " - .\n",
+            ".word ",
+            $fixup,
+            " - .\n",
+            ".popsection\n",
+        )
+    };
+}
+
+#[repr(C)]
+struct Entry {
+    insn: i32,
+    fixup: i32,
+}
+
+impl Entry {
+    fn insn(&self) -> usize {
+        (&self.insn as *const i32 as usize).wrapping_add(self.insn as isize as usize)
+    }
+
+    fn fixup(&self) -> usize {
+        (&self.fixup as *const i32 as usize).wrapping_add(self.fixup as isize as usize)
+    }
+}
+
+fn entries() -> &'static [Entry] {
+    let len = (EX_TABLE_END() - EX_TABLE_START()) / size_of::<Entry>();
+    unsafe { core::slice::from_raw_parts(EX_TABLE_START() as *const Entry, len) }
+}
+
+/// Address to continue at if the instruction at `addr` faults
+pub fn search(addr: usize) -> Option<usize> {
+    entries()
+        .iter()
+        .find(|entry| entry.insn() == addr)
+        .map(Entry::fixup)
+}
+
+/// Redirect a trap taken on an instruction with an entry to its fixup
+///
+/// Returns false if there is no entry for the instruction.
+pub fn fixup_exception(frame: &mut TrapFrame) -> bool {
+    match search(frame.sepc) {
+        Some(fixup) if frame.from_kernel() => {
+            frame.sepc = fixup;
+            true
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,254 @@
+// Framebuffer console on QEMU's ramfb
+//
+// With `-device ramfb`, QEMU shows a framebuffer in guest memory once the
+// guest writes its address and format to the fw_cfg file `etc/ramfb`. Text
+// is drawn with the 8x8 font and scrolls up a line at a time.
+//
+// The text is kept apart from the pixels. A character is drawn as it is
+// printed, but a scroll only moves the text, the pixels are redrawn from
+// the workqueue, or on flush, so the console lock is not held for a copy
+// of the whole framebuffer.
+//
+// `FBCON=1 cargo run` boots with ramfb, see scripts/run.sh.
+
+use crate::arch::riscv::without_interrupts;
+use crate::console::{self, Backend};
+use crate::font;
+use crate::fw_cfg;
+use crate::kaslr;
+use crate::page::{self, PAGE_SIZE};
+use crate::workqueue::{schedule_work, Work};
+
+use log::{info, warn};
+use spin::Mutex;
+
+const WIDTH: usize = 640;
+const HEIGHT: usize = 480;
+/// Bytes per pixel
+const BPP: usize = 4;
+const STRIDE: usize = WIDTH * BPP;
+
+const COLUMNS: usize = WIDTH / font::WIDTH;
+const ROWS: usize = HEIGHT / font::HEIGHT;
+
+/// DRM_FORMAT_XRGB8888, "XR24"
+const FORMAT_XRGB8888: u32 = 0x3432_5258;
+
+const FOREGROUND: u32 = 0x00aa_aaaa;
+const BACKGROUND: u32 = 0x0000_0000;
+
+/// Written to `etc/ramfb`, all big-endian
+#[repr(C, packed)]
+struct RamfbConfig {
+    address: u64,
+    fourcc: u32,
+    flags: u32,
+    width: u32,
+    height: u32,
+    stride: u32,
+}
+
+/// Only locked with interrupts off, by the console with the console lock
+/// held and by `redraw`
+struct Screen {
+    /// Address of the pixels, 0 until set up
+    base: usize,
+    column: usize,
+    row: usize,
+    /// The text on the screen, a ring of rows starting at `top`
+    text: [[u8; COLUMNS]; ROWS],
+    top: usize,
+    /// Set when the pixels lag behind the text, until `redraw` caught up
+    dirty: bool,
+    /// Bumped on every change of the text while dirty
+    version: usize,
+}
+
+static SCREEN: Mutex<Screen> = Mutex::new(Screen {
+    base: 0,
+    column: 0,
+    row: 0,
+    text: [[b' '; COLUMNS]; ROWS],
+    top: 0,
+    dirty: false,
+    version: 0,
+});
+
+/// Redraws the screen after it scrolled
+static REDRAW: Work = Work::new(redraw, 0);
+
+impl Screen {
+    fn pixel(&self, x: usize, y: usize) -> *mut u32 {
+        (self.base + y * STRIDE + x * BPP) as *mut u32
+    }
+
+    fn line(&mut self, row: usize) -> &mut [u8; COLUMNS] {
+        &mut self.text[(self.top + row) % ROWS]
+    }
+
+    fn draw(&self, c: u8, column: usize, row: usize) {
+        let glyph = font::glyph(c);
+        let (x, y) = (column * font::WIDTH, row * font::HEIGHT);
+        for (dy, bits) in glyph.iter().enumerate() {
+            for dx in 0..font::WIDTH {
+                let color = if bits & (1 << dx) != 0 {
+                    FOREGROUND
+                } else {
+                    BACKGROUND
+                };
+                unsafe { self.pixel(x + dx, y + dy).write_volatile(color) };
+            }
+        }
+    }
+
+    fn draw_row(&mut self, row: usize) {
+        let line = *self.line(row);
+        for (column, &c) in line.iter().enumerate() {
+            self.draw(c, column, row);
+        }
+    }
+
+    fn newline(&mut self) {
+        self.column = 0;
+        if self.row + 1 < ROWS {
+            self.row += 1;
+            return;
+        }
+
+        // Scroll up a line, the pixels follow from `redraw`
+        self.top = (self.top + 1) % ROWS;
+        *self.line(ROWS - 1) = [b' '; COLUMNS];
+        self.dirty = true;
+        self.version = self.version.wrapping_add(1);
+        schedule_work(&REDRAW);
+    }
+
+    fn put(&mut self, c: u8) {
+        match c {
+            b'\n' => self.newline(),
+            b'\r' => self.column = 0,
+            8 => self.column = self.column.saturating_sub(1),
+            b'\t' => {
+                for _ in 0..8 - self.column % 8 {
+                    self.put(b' ');
+                }
+            }
+            _ => {
+                if self.column == COLUMNS {
+                    self.newline();
+                }
+                let (column, row) = (self.column, self.row);
+                self.line(row)[column] = c;
+                if self.dirty {
+                    self.version = self.version.wrapping_add(1);
+                } else {
+                    self.draw(c, column, row);
+                }
+                self.column += 1;
+            }
+        }
+    }
+}
+
+/// Draw the text of the screen after it scrolled
+///
+/// A row at a time with the lock held, so printing is not held up for the
+/// whole screen. Starts over if the text changed meanwhile.
+fn redraw(_: usize) {
+    loop {
+        let version = without_interrupts(|| SCREEN.lock().version);
+        for row in 0..ROWS {
+            without_interrupts(|| SCREEN.lock().draw_row(row));
+        }
+
+        let done = without_interrupts(|| {
+            let mut screen = SCREEN.lock();
+            if screen.version == version {
+                screen.dirty = false;
+            }
+            !screen.dirty
+        });
+        if done {
+            return;
+        }
+    }
+}
+
+pub struct Framebuffer;
+
+pub static CONSOLE: Framebuffer = Framebuffer;
+
+impl Backend for Framebuffer {
+    fn name(&self) -> &'static str {
+        "fbcon"
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        let mut screen = SCREEN.lock();
+        if screen.base == 0 {
+            return;
+        }
+        for &c in bytes {
+            screen.put(c);
+        }
+    }
+
+    /// Catch up on scrolls right away, the workqueue may not run again
+    fn flush(&self) {
+        let mut screen = SCREEN.lock();
+        if screen.base == 0 || !screen.dirty {
+            return;
+        }
+        for row in 0..ROWS {
+            screen.draw_row(row);
+        }
+        screen.dirty = false;
+    }
+
+    unsafe fn force_unlock(&self) {
+        SCREEN.force_unlock();
+    }
+}
+
+/// Set up ramfb, if QEMU has one, and add it to the console
+pub fn init() {
+    if fw_cfg::file_size("etc/ramfb").is_none() {
+        return;
+    }
+
+    let pages = (STRIDE * HEIGHT + PAGE_SIZE - 1) / PAGE_SIZE;
+    let base = page::zalloc(pages);
+    if base.is_null() {
+        warn!("fbcon: no memory for the framebuffer");
+        return;
+    }
+
+    let config = RamfbConfig {
+        address: (kaslr::link_address(base as usize) as u64).to_be(),
+        fourcc: FORMAT_XRGB8888.to_be(),
+        flags: 0,
+        width: (WIDTH as u32).to_be(),
+        height: (HEIGHT as u32).to_be(),
+        stride: (STRIDE as u32).to_be(),
+    };
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            &config as *const RamfbConfig as *const u8,
+            core::mem::size_of::<RamfbConfig>(),
+        )
+    };
+    if let Err(err) = fw_cfg::write_file("etc/ramfb", bytes) {
+        warn!("fbcon: ramfb not set up, {:?}", err);
+        page::dealloc(base);
+        return;
+    }
+
+    SCREEN.lock().base = base as usize;
+    info!(
+        "fbcon: {}x{} ramfb, {}x{} text",
+        WIDTH, HEIGHT, COLUMNS, ROWS
+    );
+    if let Err(err) = console::register(&CONSOLE) {
+        warn!("fbcon: {:?}", err);
+    }
+}
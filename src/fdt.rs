@@ -0,0 +1,286 @@
+// Flattened device tree (FDT) reader
+//
+// QEMU hands the address of the device tree blob to the kernel in a1. Only
+// reading is supported, the blob is walked in place without allocating.
+//
+// Layout of the structure block, all values big-endian:
+//   BEGIN_NODE name\0 [padding]   PROP len nameoff value [padding]
+//   ... child nodes ...           END_NODE
+// The tree ends with an END token.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{info, warn};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+mod header {
+    pub const MAGIC: usize = 0x00;
+    pub const TOTALSIZE: usize = 0x04;
+    pub const OFF_DT_STRUCT: usize = 0x08;
+    pub const OFF_DT_STRINGS: usize = 0x0c;
+}
+
+mod token {
+    pub const BEGIN_NODE: u32 = 0x1;
+    pub const END_NODE: u32 = 0x2;
+    pub const PROP: u32 = 0x3;
+    pub const NOP: u32 = 0x4;
+}
+
+/// Address of the device tree handed over at boot, 0 if there is none
+static FDT_ADDR: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy)]
+pub struct Fdt {
+    data: &'static [u8],
+    structs: usize,
+    strings: usize,
+}
+
+/// A node of the tree
+#[derive(Clone, Copy)]
+pub struct Node {
+    fdt: Fdt,
+    name: &'static str,
+    /// Offset of the first token after the node name
+    body: usize,
+}
+
+/// A property of a node
+#[derive(Clone, Copy)]
+pub struct Property {
+    name: &'static str,
+    value: &'static [u8],
+}
+
+impl Fdt {
+    /// Read the device tree at `addr`
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be readable for as long as the kernel runs.
+    pub unsafe fn from_addr(addr: usize) -> Option<Self> {
+        let header = core::slice::from_raw_parts(addr as *const u8, 0x28);
+        if be32(header, header::MAGIC) != FDT_MAGIC {
+            return None;
+        }
+
+        let size = be32(header, header::TOTALSIZE) as usize;
+        let data = core::slice::from_raw_parts(addr as *const u8, size);
+        Some(Self {
+            data,
+            structs: be32(data, header::OFF_DT_STRUCT) as usize,
+            strings: be32(data, header::OFF_DT_STRINGS) as usize,
+        })
+    }
+
+    pub fn addr(&self) -> usize {
+        self.data.as_ptr() as usize
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn root(&self) -> Option<Node> {
+        let offset = self.skip_nops(self.structs);
+        match self.token(offset) {
+            token::BEGIN_NODE => Some(self.node_at(offset)),
+            _ => None,
+        }
+    }
+
+    /// Look up a node by path, such as `/chosen` or `/soc/serial@10000000`
+    ///
+    /// A path component without a unit address matches the first node with
+    /// that name, `/soc/serial` matches `/soc/serial@10000000`.
+    pub fn find_node(&self, path: &str) -> Option<Node> {
+        path.split('/')
+            .filter(|component| !component.is_empty())
+            .try_fold(self.root()?, |node, component| {
+                node.children().find(|child| child.matches(component))
+            })
+    }
+
+    /// Look up a property of the node at `path`
+    pub fn property(&self, path: &str, name: &str) -> Option<Property> {
+        self.find_node(path)?.property(name)
+    }
+
+    fn token(&self, offset: usize) -> u32 {
+        be32(self.data, offset)
+    }
+
+    fn skip_nops(&self, mut offset: usize) -> usize {
+        while self.token(offset) == token::NOP {
+            offset += 4;
+        }
+        offset
+    }
+
+    /// The node whose BEGIN_NODE token is at `offset`
+    fn node_at(&self, offset: usize) -> Node {
+        let name = self.cstr(offset + 4);
+        Node {
+            fdt: *self,
+            name,
+            body: align4(offset + 4 + name.len() + 1),
+        }
+    }
+
+    /// The property whose PROP token is at `offset` and the offset after it
+    fn property_at(&self, offset: usize) -> (Property, usize) {
+        let len = be32(self.data, offset + 4) as usize;
+        let name = self.cstr(self.strings + be32(self.data, offset + 8) as usize);
+        let value = &self.data[offset + 12..offset + 12 + len];
+        (Property { name, value }, align4(offset + 12 + len))
+    }
+
+    /// Offset after the END_NODE token of the node with body at `offset`
+    fn skip_node(&self, mut offset: usize) -> usize {
+        let mut depth = 1;
+        while depth > 0 {
+            offset = match self.token(offset) {
+                token::BEGIN_NODE => {
+                    depth += 1;
+                    self.node_at(offset).body
+                }
+                token::END_NODE => {
+                    depth -= 1;
+                    offset + 4
+                }
+                token::PROP => self.property_at(offset).1,
+                token::NOP => offset + 4,
+                _ => return self.data.len(),
+            };
+        }
+        offset
+    }
+
+    fn cstr(&self, offset: usize) -> &'static str {
+        let bytes = &self.data[offset.min(self.data.len())..];
+        let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+        core::str::from_utf8(&bytes[..len]).unwrap_or("")
+    }
+}
+
+impl Node {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Iterate over the properties of the node
+    pub fn properties(&self) -> impl Iterator<Item = Property> {
+        let fdt = self.fdt;
+        let mut offset = self.body;
+        core::iter::from_fn(move || {
+            offset = fdt.skip_nops(offset);
+            if offset >= fdt.data.len() || fdt.token(offset) != token::PROP {
+                return None;
+            }
+            let (property, next) = fdt.property_at(offset);
+            offset = next;
+            Some(property)
+        })
+    }
+
+    pub fn property(&self, name: &str) -> Option<Property> {
+        self.properties().find(|property| property.name == name)
+    }
+
+    /// Iterate over the direct children of the node
+    pub fn children(&self) -> impl Iterator<Item = Node> {
+        let fdt = self.fdt;
+        let mut offset = self.body;
+        core::iter::from_fn(move || loop {
+            if offset >= fdt.data.len() {
+                return None;
+            }
+            match fdt.token(offset) {
+                token::PROP => offset = fdt.property_at(offset).1,
+                token::NOP => offset += 4,
+                token::BEGIN_NODE => {
+                    let child = fdt.node_at(offset);
+                    offset = fdt.skip_node(child.body);
+                    return Some(child);
+                }
+                _ => return None,
+            }
+        })
+    }
+
+    fn matches(&self, component: &str) -> bool {
+        if component.contains('@') {
+            self.name == component
+        } else {
+            self.name.split('@').next() == Some(component)
+        }
+    }
+}
+
+impl Property {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn value(&self) -> &'static [u8] {
+        self.value
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        (self.value.len() >= 4).then(|| be32(self.value, 0))
+    }
+
+    /// Read a one or two cell value
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.value.len() {
+            4 => Some(be32(self.value, 0) as u64),
+            8 => Some((be32(self.value, 0) as u64) << 32 | be32(self.value, 4) as u64),
+            _ => None,
+        }
+    }
+
+    /// Read a string property, without the terminating NUL
+    pub fn as_str(&self) -> Option<&'static str> {
+        let value = match self.value.split_last() {
+            Some((0, value)) => value,
+            _ => self.value,
+        };
+        core::str::from_utf8(value).ok()
+    }
+}
+
+const fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn be32(data: &[u8], offset: usize) -> u32 {
+    match data.get(offset..offset + 4) {
+        Some(bytes) => u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        None => 0,
+    }
+}
+
+/// The device tree handed over at boot
+pub fn fdt() -> Option<Fdt> {
+    match FDT_ADDR.load(Ordering::Relaxed) {
+        0 => None,
+        addr => unsafe { Fdt::from_addr(addr) },
+    }
+}
+
+/// Initiate the device tree from the address handed over at boot
+///
+/// # Safety
+///
+/// `addr` must point to memory that stays readable.
+pub unsafe fn init(addr: usize) {
+    match Fdt::from_addr(addr) {
+        Some(fdt) => {
+            info!("Device tree at 0x{:X} ({} bytes)", addr, fdt.size());
+            FDT_ADDR.store(addr, Ordering::Relaxed);
+        }
+        None => warn!("No device tree at 0x{:X}", addr),
+    }
+}
@@ -0,0 +1,318 @@
+// QEMU firmware configuration (fw_cfg) device
+//
+// An item is selected by writing its key to the selector register, after
+// which its contents are read from the data register one access at a time,
+// or copied in one go through the DMA interface. Files handed to QEMU with
+// `-fw_cfg name=opt/...,file=...` are listed in the file directory item.
+//
+// Selector, directory entries and DMA descriptors are all big-endian.
+
+use crate::kaslr;
+
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+
+use log::{info, warn};
+use spin::Mutex;
+
+pub const FW_CFG_BASE: usize = 0x1010_0000;
+pub const FW_CFG_SIZE: usize = 0x1000;
+
+mod offset {
+    pub const DATA: usize = 0x00;
+    pub const SELECTOR: usize = 0x08;
+    pub const DMA_HIGH: usize = 0x10;
+    pub const DMA_LOW: usize = 0x14;
+}
+
+mod key {
+    pub const SIGNATURE: u16 = 0x0000;
+    pub const ID: u16 = 0x0001;
+    pub const FILE_DIR: u16 = 0x0019;
+}
+
+mod control {
+    pub const ERROR: u32 = 1 << 0;
+    pub const READ: u32 = 1 << 1;
+    pub const SELECT: u32 = 1 << 3;
+    pub const WRITE: u32 = 1 << 4;
+}
+
+const SIGNATURE: &[u8; 4] = b"QEMU";
+const FEATURE_DMA: u32 = 1 << 1;
+const FILE_NAME_LEN: usize = 56;
+
+static FW_CFG: Mutex<FwCfg> = Mutex::new(FwCfg::new(FW_CFG_BASE));
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Error {
+    /// No fw_cfg device was found
+    NoDevice,
+    /// No file with the given name
+    NotFound,
+    /// The device does not support DMA
+    NoDma,
+    /// The device reported an error for a DMA transfer
+    Dma,
+}
+
+/// DMA descriptor, shared with the device
+#[repr(C)]
+struct DmaAccess {
+    control: u32,
+    length: u32,
+    address: u64,
+}
+
+/// An entry of the file directory
+#[derive(Clone, Copy)]
+pub struct File {
+    size: u32,
+    select: u16,
+    name: [u8; FILE_NAME_LEN],
+}
+
+impl File {
+    /// Name of the file, such as `opt/rost/config`
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(FILE_NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("<invalid>")
+    }
+
+    pub const fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    pub const fn select(&self) -> u16 {
+        self.select
+    }
+}
+
+pub struct FwCfg {
+    base_address: usize,
+    present: bool,
+    dma: bool,
+}
+
+impl FwCfg {
+    pub const fn new(base_address: usize) -> Self {
+        Self {
+            base_address,
+            present: false,
+            dma: false,
+        }
+    }
+
+    /// Check the signature and the supported features of the device
+    pub fn probe(&mut self) -> bool {
+        let mut signature = [0u8; 4];
+        self.select(key::SIGNATURE);
+        self.read_data(&mut signature);
+        self.present = &signature == SIGNATURE;
+
+        if self.present {
+            let mut id = [0u8; 4];
+            self.select(key::ID);
+            self.read_data(&mut id);
+            self.dma = u32::from_le_bytes(id) & FEATURE_DMA != 0;
+        }
+
+        self.present
+    }
+
+    pub const fn has_dma(&self) -> bool {
+        self.dma
+    }
+
+    /// Iterate over the file directory
+    pub fn files(&mut self) -> Files<'_> {
+        let mut count = [0u8; 4];
+        if self.present {
+            self.select(key::FILE_DIR);
+            self.read_data(&mut count);
+        }
+
+        Files {
+            fw_cfg: self,
+            remaining: u32::from_be_bytes(count),
+        }
+    }
+
+    /// Look up a file by name
+    pub fn find(&mut self, name: &str) -> Option<File> {
+        self.files().find(|file| file.name() == name)
+    }
+
+    /// Read the start of `file` into `buf`
+    ///
+    /// Uses DMA when the device supports it. Returns the number of bytes read.
+    pub fn read(&mut self, file: &File, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = buf.len().min(file.size());
+        if self.dma {
+            self.read_dma(file.select(), &mut buf[..len])?;
+        } else {
+            self.select(file.select());
+            self.read_data(&mut buf[..len]);
+        }
+        Ok(len)
+    }
+
+    /// Select item `key` and copy the start of it into `buf` through DMA
+    ///
+    /// The kernel is identity mapped, apart from the KASLR mapping of the
+    /// image, so the physical addresses of the descriptor and `buf` are
+    /// easy to find.
+    pub fn read_dma(&mut self, key: u16, buf: &mut [u8]) -> Result<(), Error> {
+        self.dma(key, control::READ, buf.as_mut_ptr() as usize, buf.len())
+    }
+
+    /// Select item `key` and overwrite the start of it with `buf`
+    ///
+    /// Writes are only supported through DMA, and only to some items.
+    pub fn write_dma(&mut self, key: u16, buf: &[u8]) -> Result<(), Error> {
+        self.dma(key, control::WRITE, buf.as_ptr() as usize, buf.len())
+    }
+
+    fn dma(&mut self, key: u16, op: u32, addr: usize, len: usize) -> Result<(), Error> {
+        if !self.dma {
+            return Err(Error::NoDma);
+        }
+
+        let mut access = DmaAccess {
+            control: ((key as u32) << 16 | control::SELECT | op).to_be(),
+            length: (len as u32).to_be(),
+            address: (kaslr::link_address(addr) as u64).to_be(),
+        };
+        // The device writes `control`, from here on only used through the
+        // raw pointer
+        let access = ptr::addr_of_mut!(access);
+        let addr = kaslr::link_address(access as usize) as u64;
+
+        fence(Ordering::SeqCst);
+        // The transfer starts on the write to the low half
+        self.write_reg(offset::DMA_HIGH, ((addr >> 32) as u32).to_be());
+        self.write_reg(offset::DMA_LOW, (addr as u32).to_be());
+
+        let control = unsafe { ptr::addr_of_mut!((*access).control) };
+        loop {
+            let control = u32::from_be(unsafe { control.read_volatile() });
+            if control & control::ERROR != 0 {
+                return Err(Error::Dma);
+            }
+            if control == 0 {
+                break;
+            }
+        }
+        fence(Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    fn select(&mut self, key: u16) {
+        let ptr = (self.base_address + offset::SELECTOR) as *mut u16;
+        unsafe {
+            ptr.write_volatile(key.to_be());
+        }
+    }
+
+    /// Read from the data register of the selected item
+    fn read_data(&mut self, buf: &mut [u8]) {
+        let ptr = (self.base_address + offset::DATA) as *const u8;
+        for byte in buf.iter_mut() {
+            *byte = unsafe { ptr.read_volatile() };
+        }
+    }
+
+    fn write_reg(&mut self, offset: usize, value: u32) {
+        let ptr = (self.base_address + offset) as *mut u32;
+        unsafe {
+            ptr.write_volatile(value);
+        }
+    }
+}
+
+/// Iterator over the file directory, see [`FwCfg::files`]
+pub struct Files<'a> {
+    fw_cfg: &'a mut FwCfg,
+    remaining: u32,
+}
+
+impl Iterator for Files<'_> {
+    type Item = File;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let mut size = [0u8; 4];
+        let mut select = [0u8; 2];
+        let mut reserved = [0u8; 2];
+        let mut name = [0u8; FILE_NAME_LEN];
+        self.fw_cfg.read_data(&mut size);
+        self.fw_cfg.read_data(&mut select);
+        self.fw_cfg.read_data(&mut reserved);
+        self.fw_cfg.read_data(&mut name);
+
+        Some(File {
+            size: u32::from_be_bytes(size),
+            select: u16::from_be_bytes(select),
+            name,
+        })
+    }
+}
+
+/// Read the start of the file `name` into `buf`
+///
+/// Returns the number of bytes read.
+pub fn read_file(name: &str, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut fw_cfg = FW_CFG.lock();
+    if !fw_cfg.present {
+        return Err(Error::NoDevice);
+    }
+    let file = fw_cfg.find(name).ok_or(Error::NotFound)?;
+    fw_cfg.read(&file, buf)
+}
+
+/// Overwrite the start of the file `name` with `buf`
+pub fn write_file(name: &str, buf: &[u8]) -> Result<(), Error> {
+    let mut fw_cfg = FW_CFG.lock();
+    if !fw_cfg.present {
+        return Err(Error::NoDevice);
+    }
+    let file = fw_cfg.find(name).ok_or(Error::NotFound)?;
+    fw_cfg.write_dma(file.select(), buf)
+}
+
+/// Size of the file `name`
+pub fn file_size(name: &str) -> Option<usize> {
+    FW_CFG.lock().find(name).map(|file| file.size())
+}
+
+/// Initiate fw_cfg and list the files handed over by the host
+pub fn init() {
+    info!("Initating fw_cfg");
+    let mut fw_cfg = FW_CFG.lock();
+    if !fw_cfg.probe() {
+        warn!("fw_cfg: no device found");
+        return;
+    }
+
+    info!(
+        "fw_cfg: DMA {}",
+        if fw_cfg.has_dma() { "on" } else { "off" }
+    );
+    for file in fw_cfg.files() {
+        info!(
+            "\t{:04x}: {} ({} bytes)",
+            file.select(),
+            file.name(),
+            file.size()
+        );
+    }
+}
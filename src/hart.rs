@@ -0,0 +1,354 @@
+// Hart state management
+//
+// Follows the state machine of the SBI Hart State Management (HSM)
+// extension. A stopped hart is parked in `wfi` and is woken up through its
+// CLINT msip register when it is started again. A started hart resumes at
+// the given entry point on a fresh stack.
+
+use crate::arch::riscv::{thread_pointer, wait};
+use crate::clint;
+use crate::clocksource::Instant;
+use crate::cmdline;
+use crate::plic::{self, InterruptId};
+use crate::symbols::{HART_STACK_SIZE, KERNEL_STACK_START};
+use crate::tick;
+use crate::timer;
+use crate::trap;
+use crate::watchdog;
+
+use core::arch::asm;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use log::{info, warn};
+use spin::{Mutex, MutexGuard};
+
+pub const MAX_HARTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum State {
+    Started = 0,
+    Stopped = 1,
+    StartPending = 2,
+    StopPending = 3,
+    /// The hart has not checked in yet, it might not exist
+    Absent = 4,
+}
+
+impl From<usize> for State {
+    fn from(state: usize) -> Self {
+        match state {
+            0 => Self::Started,
+            1 => Self::Stopped,
+            2 => Self::StartPending,
+            3 => Self::StopPending,
+            _ => Self::Absent,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Error {
+    InvalidHart,
+    AlreadyStarted,
+    AlreadyStopped,
+    /// Refused to stop the last started hart
+    LastHart,
+}
+
+/// A set of harts
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct HartMask(usize);
+
+impl HartMask {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn all() -> Self {
+        Self((1 << MAX_HARTS) - 1)
+    }
+
+    pub const fn single(hart: usize) -> Self {
+        Self(1 << hart)
+    }
+
+    pub const fn from_bits(bits: usize) -> Self {
+        Self(bits & Self::all().0)
+    }
+
+    pub const fn bits(&self) -> usize {
+        self.0
+    }
+
+    pub fn set(&mut self, hart: usize) {
+        assert!(hart < MAX_HARTS);
+        self.0 |= 1 << hart;
+    }
+
+    pub fn clear(&mut self, hart: usize) {
+        self.0 &= !(1 << hart);
+    }
+
+    pub const fn contains(&self, hart: usize) -> bool {
+        hart < MAX_HARTS && self.0 & (1 << hart) != 0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn count(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// Iterate over the harts in the mask, lowest id first
+    pub fn iter(&self) -> impl Iterator<Item = usize> {
+        let mask = *self;
+        (0..MAX_HARTS).filter(move |&hart| mask.contains(hart))
+    }
+}
+
+const ABSENT: AtomicUsize = AtomicUsize::new(State::Absent as usize);
+const NO_ENTRY: AtomicUsize = AtomicUsize::new(0);
+
+static STATE: [AtomicUsize; MAX_HARTS] = [ABSENT; MAX_HARTS];
+static ENTRY: [AtomicUsize; MAX_HARTS] = [NO_ENTRY; MAX_HARTS];
+
+/// Serializes stopping harts so that the last one can not go away
+static HOTPLUG: Mutex<()> = Mutex::new(());
+
+/// Hart that `hart_test` stops and starts again
+const TEST_HART: usize = 1;
+/// How long `hart_test` waits for the hart to change state
+const TEST_TIMEOUT: Duration = Duration::from_secs(1);
+/// Set once the test hart went to stop
+static TEST_STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Current state of a hart
+pub fn state(hart: usize) -> State {
+    match STATE.get(hart) {
+        Some(state) => State::from(state.load(Ordering::Acquire)),
+        None => State::Absent,
+    }
+}
+
+/// Harts that are started
+pub fn online_mask() -> HartMask {
+    let mut mask = HartMask::empty();
+    (0..MAX_HARTS)
+        .filter(|&hart| state(hart) == State::Started)
+        .for_each(|hart| mask.set(hart));
+    mask
+}
+
+/// Keep the started harts from stopping while the guard is held
+///
+/// `stop` waits for the guard, with interrupts enabled, so the holder can
+/// still wait for IPIs to be served.
+pub fn hotplug_lock() -> MutexGuard<'static, ()> {
+    HOTPLUG.lock()
+}
+
+/// Mark the boot hart as started
+pub fn boot() {
+    STATE[thread_pointer()].store(State::Started as usize, Ordering::Release);
+}
+
+/// Wait until `deadline` for `hart` to check in
+///
+/// Secondary harts check in when they first park. Returns false if the hart
+/// did not, it might not exist.
+pub fn wait_present(hart: usize, deadline: Instant) -> bool {
+    while state(hart) == State::Absent {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        spin_loop();
+    }
+    true
+}
+
+/// Start a stopped hart
+///
+/// The hart goes through `entry` on a fresh stack, with interrupts disabled.
+pub fn start(hart: usize, entry: fn() -> !) -> Result<(), Error> {
+    if hart >= MAX_HARTS {
+        return Err(Error::InvalidHart);
+    }
+
+    STATE[hart]
+        .compare_exchange(
+            State::Stopped as usize,
+            State::StartPending as usize,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .map_err(|state| match State::from(state) {
+            State::Absent => Error::InvalidHart,
+            _ => Error::AlreadyStarted,
+        })?;
+    // Only once the hart is ours, the hart waits for it in `park`
+    ENTRY[hart].store(entry as usize, Ordering::Release);
+
+    clint::send_soft(hart);
+    Ok(())
+}
+
+/// Stop the current hart
+///
+/// Its PLIC interrupts and pending timers are migrated to another started
+/// hart and its timer event is stopped before the hart is parked. Does not return on success, the hart
+/// continues at the entry given to [`start`].
+pub fn stop() -> Result<(), Error> {
+    let hart = thread_pointer();
+
+    {
+        let _guard = HOTPLUG.lock();
+        let target = online_mask()
+            .iter()
+            .find(|&other| other != hart)
+            .ok_or(Error::LastHart)?;
+
+        STATE[hart]
+            .compare_exchange(
+                State::Started as usize,
+                State::StopPending as usize,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .map_err(|_| Error::AlreadyStopped)?;
+
+        unsafe {
+            trap::disable_interrupts();
+        }
+        plic::migrate(hart, target);
+        if let Err(err) = timer::migrate(hart, target) {
+            warn!("timers of hart {} not all moved, {:?}", hart, err);
+        }
+        watchdog::stop();
+        tick::stop();
+        info!("hart {} offline, interrupts moved to hart {}", hart, target);
+    }
+
+    park()
+}
+
+/// Park the current hart until it is started
+///
+/// Secondary harts park here at boot until the boot hart starts them.
+pub fn park() -> ! {
+    let hart = thread_pointer();
+    STATE[hart].store(State::Stopped as usize, Ordering::Release);
+
+    while STATE[hart]
+        .compare_exchange(
+            State::StartPending as usize,
+            State::Started as usize,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .is_err()
+    {
+        wait();
+    }
+
+    // `start` stores the entry right after the state
+    let entry = loop {
+        match ENTRY[hart].swap(0, Ordering::Acquire) {
+            0 => spin_loop(),
+            entry => break entry,
+        }
+    };
+    let stack = KERNEL_STACK_START() - hart * HART_STACK_SIZE();
+    unsafe {
+        asm!(
+            "mv sp, {stack}",
+            "jr {entry}",
+            stack = in(reg) stack,
+            entry = in(reg) entry,
+            options(noreturn)
+        );
+    }
+}
+
+/// Wait until `deadline` for `hart` to be in `state`
+fn wait_state(hart: usize, state: State, deadline: Instant) -> bool {
+    while self::state(hart) != state {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        spin_loop();
+    }
+    true
+}
+
+/// The part of `hart_test` on the tested hart
+///
+/// The first time the hart comes up, it takes over the RTC interrupt and
+/// stops.
+pub fn test_stop() {
+    let hart = thread_pointer();
+    if hart != TEST_HART || !cmdline::has("hart_test") || TEST_STOPPED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if let Err(err) = plic::set_affinity(InterruptId::Rtc, HartMask::single(hart)) {
+        warn!("hart_test: RTC not moved to hart {}, {:?}", hart, err);
+    }
+    TEST_STOPPED.store(true, Ordering::Release);
+    if let Err(err) = stop() {
+        warn!("hart_test: hart {} did not stop, {:?}", hart, err);
+    }
+}
+
+/// Check that a hart stops and starts again, with `hart_test`
+///
+/// Waits for the tested hart to stop itself in `test_stop`, checks that its
+/// interrupts went to another hart, and starts it again at `entry`.
+pub fn test(entry: fn() -> !) {
+    if !cmdline::has("hart_test") {
+        return;
+    }
+
+    let deadline = Instant::now() + TEST_TIMEOUT;
+    let stopped = loop {
+        if TEST_STOPPED.load(Ordering::Acquire) {
+            break wait_state(TEST_HART, State::Stopped, deadline);
+        }
+        if Instant::now() >= deadline {
+            break false;
+        }
+        spin_loop();
+    };
+    if !stopped {
+        warn!("hart_test: hart {} did not stop", TEST_HART);
+        info!("hart_test: FAILED");
+        return;
+    }
+
+    let route = plic::routes().find(|route| route.id == InterruptId::Rtc);
+    let migrated = route.map_or(false, |route| {
+        !route.affinity.contains(TEST_HART)
+            && !route.enabled.contains(TEST_HART)
+            && route.enabled.bits() & online_mask().bits() != 0
+    });
+    if !migrated {
+        warn!("hart_test: RTC interrupt not migrated, {:?}", route);
+    }
+
+    let started = start(TEST_HART, entry).is_ok()
+        && wait_state(TEST_HART, State::Started, Instant::now() + TEST_TIMEOUT);
+    if !started {
+        warn!("hart_test: hart {} did not start again", TEST_HART);
+    }
+    info!(
+        "hart_test: {}",
+        if migrated && started {
+            "passed"
+        } else {
+            "FAILED"
+        }
+    );
+}
@@ -1,38 +1,309 @@
-use crate::arch::riscv;
-use crate::plic::{self, InterruptId};
-use crate::uart::uart_interrupt;
+use crate::arch::riscv::{clear_sie_ssoft, thread_pointer};
+use crate::clint;
+use crate::hart::MAX_HARTS;
+use crate::ipi;
+use crate::plic::{self, InterruptId, Priority, Threshold, PLIC_SOURCES};
+use crate::softirq::{self, SoftIrq};
+use crate::stats;
+use crate::tick;
+use crate::timer;
+
+use core::arch::global_asm;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use log::{debug, error};
+use riscv::register::{sie, sstatus};
+use spin::Mutex;
+
+/// Number of handlers that can share a PLIC source
+const MAX_SHARED: usize = 4;
+
+/// Size of the per hart stack interrupts are handled on
+const IRQ_STACK_SIZE: usize = 0x4000;
+/// Deepest interrupt nesting, one more than there are PLIC priority levels
+const MAX_DEPTH: usize = 8;
+
+#[repr(C, align(16))]
+struct IrqStack([u8; IRQ_STACK_SIZE]);
+
+const EMPTY_STACK: IrqStack = IrqStack([0; IRQ_STACK_SIZE]);
+
+static mut IRQ_STACKS: [IrqStack; MAX_HARTS] = [EMPTY_STACK; MAX_HARTS];
+
+const NOT_NESTED: AtomicUsize = AtomicUsize::new(0);
+
+/// Interrupt nesting depth per hart
+static DEPTH: [AtomicUsize; MAX_HARTS] = [NOT_NESTED; MAX_HARTS];
+
+/// Handler of a PLIC interrupt
+///
+/// Gets the source and the data given to `request_irq`. On a shared line
+/// every handler is called, each checks if its device raised the interrupt.
+pub type IrqHandler = fn(InterruptId, usize) -> IrqReturn;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum IrqReturn {
+    /// The interrupt was not from this device
+    None,
+    Handled,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Error {
+    /// Not a PLIC source on this machine
+    InvalidSource,
+    /// Every handler slot of the source is taken
+    Busy,
+    /// No handler with the given data
+    NotFound,
+}
+
+/// A handler registered for a source, the handler is 0 for a free slot
+struct Action {
+    handler: AtomicUsize,
+    data: AtomicUsize,
+}
+
+impl Action {
+    const fn new() -> Self {
+        Self {
+            handler: AtomicUsize::new(0),
+            data: AtomicUsize::new(0),
+        }
+    }
+
+    fn handler(&self) -> Option<IrqHandler> {
+        match self.handler.load(Ordering::Acquire) {
+            0 => None,
+            handler => Some(unsafe { core::mem::transmute::<usize, IrqHandler>(handler) }),
+        }
+    }
+}
+
+const NO_ACTION: Action = Action::new();
+const NO_ACTIONS: [Action; MAX_SHARED] = [NO_ACTION; MAX_SHARED];
+
+/// Handlers indexed by PLIC source
+///
+/// Read without locking when dispatching, `REGISTER` only orders the
+/// writers.
+static ACTIONS: [[Action; MAX_SHARED]; PLIC_SOURCES] = [NO_ACTIONS; PLIC_SOURCES];
+static REGISTER: Mutex<()> = Mutex::new(());
+
+/// Register `handler` for interrupts from `source`
+///
+/// The source is routed to the harts of its affinity when its first
+/// handler is registered. Several handlers may share a source.
+pub fn request_irq(source: InterruptId, handler: IrqHandler, data: usize) -> Result<(), Error> {
+    if source == InterruptId::Unknown {
+        return Err(Error::InvalidSource);
+    }
+
+    let _guard = REGISTER.lock();
+    let actions = &ACTIONS[source as usize];
+    let first = actions.iter().all(|action| action.handler().is_none());
+    let action = actions
+        .iter()
+        .find(|action| action.handler().is_none())
+        .ok_or(Error::Busy)?;
+
+    action.data.store(data, Ordering::Relaxed);
+    action.handler.store(handler as usize, Ordering::Release);
+
+    if first {
+        unsafe {
+            plic::plic().init(source);
+        }
+        plic::route(source);
+    }
+
+    debug!("Registered handler for {:?}", source);
+    Ok(())
+}
+
+/// Remove the handler registered with `data` for `source`
+///
+/// The source is disabled when its last handler is removed.
+pub fn free_irq(source: InterruptId, data: usize) -> Result<(), Error> {
+    if source == InterruptId::Unknown {
+        return Err(Error::InvalidSource);
+    }
+
+    let _guard = REGISTER.lock();
+    let actions = &ACTIONS[source as usize];
+    let action = actions
+        .iter()
+        .find(|action| action.handler().is_some() && action.data.load(Ordering::Relaxed) == data)
+        .ok_or(Error::NotFound)?;
+    action.handler.store(0, Ordering::Release);
+
+    if actions.iter().all(|action| action.handler().is_none()) {
+        plic::unroute(source);
+    }
+
+    Ok(())
+}
 
 /// Handle an interrupt from PLIC
 fn plic_interrupt() {
     let plic = plic::plic();
-    if let Some(id) = plic.next() {
-        match id {
-            InterruptId::Uart0 => uart_interrupt(),
-            InterruptId::Unknown => error!("Unkown PLIC interrupt ({:?}", id),
+    let irq = plic.claim();
+    if irq == 0 {
+        return;
+    }
+    let start = stats::start();
+
+    let id = InterruptId::from(irq);
+    if id == InterruptId::Unknown {
+        error!("Unhandled PLIC interrupt {} ({:?})", irq, id);
+        plic.complete(irq);
+        return;
+    }
+
+    // Only PLIC interrupts of a higher priority may preempt this one. The
+    // timer and IPIs wait until the handlers are done, handlers count on
+    // nothing else running on the hart meanwhile.
+    let hart = thread_pointer();
+    let threshold = plic.threshold(hart);
+    let priority = Priority::from(plic.priority(id));
+    plic.set_threshold_on(hart, Threshold::Level(priority));
+    let enabled = sie::read();
+    unsafe {
+        sie::clear_ssoft();
+        sie::clear_stimer();
+        sstatus::set_sie();
+    }
+
+    let mut handled = false;
+    for action in ACTIONS[irq as usize].iter() {
+        if let Some(handler) = action.handler() {
+            let data = action.data.load(Ordering::Relaxed);
+            handled |= handler(id, data) == IrqReturn::Handled;
+        }
+    }
+
+    unsafe {
+        sstatus::clear_sie();
+        if enabled.ssoft() {
+            sie::set_ssoft();
         }
-        plic.complete(id as u32);
+        if enabled.stimer() {
+            sie::set_stimer();
+        }
+    }
+    plic.set_threshold_on(hart, Threshold::Level(Priority::from(threshold)));
+
+    if !handled {
+        error!("Unhandled PLIC interrupt {} ({:?})", irq, id);
     }
+    plic.complete(irq);
+    stats::record_plic(irq, start);
 }
 
-/// Handle a software timer interrupt
+/// Handle a timer interrupt, taken directly with Sstc or else forwarded
+/// from machine mode
 fn timer_interrupt() {
-    debug!("Tick");
+    tick::handle();
+}
+
+/// Handle a supervisor software interrupt
+///
+/// Raised by machine mode for both timer ticks and IPIs.
+fn software_interrupt() {
+    // Clear first, so that nothing raised while handling is lost
     unsafe {
-        riscv::clear_sie_ssoft();
+        clear_sie_ssoft();
     };
+
+    if clint::take_tick() {
+        timer_interrupt();
+    }
+    ipi::handle_ipi();
+}
+
+extern "C" fn dispatch(code: usize) {
+    match code {
+        9 => plic_interrupt(),
+        5 => timer_interrupt(),
+        1 => software_interrupt(),
+        _ => error!("Unknown Interrupt Code: {}", code),
+    }
+}
+
+extern "C" {
+    /// Run `func` with `arg` on the stack ending at `top`
+    fn call_on_stack(top: usize, func: extern "C" fn(usize), arg: usize);
+}
+
+global_asm!(
+    r#"
+.global call_on_stack
+.align 4
+call_on_stack:
+    # Keep the old sp and ra at the top of the new stack
+    addi a0, a0, -16
+    sd ra, 8(a0)
+    sd sp, 0(a0)
+    mv sp, a0
+
+    mv a0, a2
+    jalr a1
+
+    ld ra, 8(sp)
+    ld sp, 0(sp)
+    ret
+"#
+);
+
+fn irq_stack_top(hart: usize) -> usize {
+    unsafe { addr_of!(IRQ_STACKS[hart]) as usize + IRQ_STACK_SIZE }
+}
+
+/// Check if `addr` is on the IRQ stack of any hart
+pub fn on_irq_stack(addr: usize) -> bool {
+    let start = irq_stack_top(0) - IRQ_STACK_SIZE;
+    (start..start + IRQ_STACK_SIZE * MAX_HARTS).contains(&addr)
+}
+
+/// Interrupt nesting depth of the current hart, 0 outside of interrupts
+pub fn irq_depth() -> usize {
+    DEPTH[thread_pointer()].load(Ordering::Relaxed)
+}
+
+pub fn in_interrupt() -> bool {
+    irq_depth() != 0
 }
 
 /// Handle a external interrupt
 ///
-/// Either a plic interrupt or a timer interrupt forwarded from
-/// machine mode
+/// Either a plic interrupt or a timer interrupt or IPI forwarded from
+/// machine mode. The outermost interrupt switches to the IRQ stack of the
+/// hart, PLIC interrupts of a higher priority nest on top of it. Pending
+/// softirqs run when the outermost interrupt is done.
 #[no_mangle]
 pub fn handle_interrupt(code: u32) {
-    match code {
-        9 => plic_interrupt(),
-        1 => timer_interrupt(),
-        _ => error!("Unknown Interrupt Code: {}", code),
+    let start = stats::start();
+    let hart = thread_pointer();
+    let depth = DEPTH[hart].fetch_add(1, Ordering::Relaxed);
+    if depth >= MAX_DEPTH {
+        panic!("Interrupts nested too deep on hart {}", hart);
     }
+
+    if depth == 0 {
+        unsafe { call_on_stack(irq_stack_top(hart), dispatch, code as usize) };
+    } else {
+        dispatch(code as usize);
+    }
+
+    stats::record_interrupt(code as usize, start);
+    DEPTH[hart].fetch_sub(1, Ordering::Relaxed);
+    if depth == 0 {
+        softirq::do_softirq();
+    }
+}
+
+/// Register the bottom halves of the interrupts handled here
+pub fn init() {
+    softirq::open_softirq(SoftIrq::Timer, timer::run_timers);
 }
@@ -0,0 +1,86 @@
+// Inter-processor interrupts (IPI)
+//
+// The sender marks the IPI as pending for the target and writes the msip
+// register of the target in the CLINT. Machine mode on the target clears
+// msip and raises a supervisor software interrupt, where the pending IPIs
+// are handled. Timer ticks arrive the same way but are flagged apart, see
+// `clint::take_tick`.
+
+use crate::arch::riscv::thread_pointer;
+use crate::clint;
+use crate::hart::{self, HartMask, MAX_HARTS};
+use crate::smp;
+use crate::tick;
+use crate::watchdog;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::warn;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Ipi {
+    /// Run the function queued by `smp::smp_call_function`
+    CallFunction = 0,
+    /// The heartbeat of `watchdog`
+    Watchdog = 1,
+    /// Reprogram the timer event after `timer::migrate`
+    Timer = 2,
+}
+
+impl Ipi {
+    const fn bit(self) -> usize {
+        1 << self as usize
+    }
+}
+
+const NONE_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// IPIs waiting to be handled, as bits of `Ipi`, per hart
+static PENDING: [AtomicUsize; MAX_HARTS] = [NONE_PENDING; MAX_HARTS];
+
+/// Send an IPI to a hart
+pub fn send(hart: usize, ipi: Ipi) {
+    match PENDING.get(hart) {
+        Some(pending) => {
+            pending.fetch_or(ipi.bit(), Ordering::Release);
+            clint::send_soft(hart);
+        }
+        None => warn!("IPI {:?} to invalid hart {}", ipi, hart),
+    }
+}
+
+/// Send an IPI to every hart in `mask`
+pub fn send_mask(mask: HartMask, ipi: Ipi) {
+    for hart in mask.iter() {
+        send(hart, ipi);
+    }
+}
+
+/// Send an IPI to every started hart, including the current one
+pub fn send_all(ipi: Ipi) {
+    send_mask(hart::online_mask(), ipi);
+}
+
+/// Send an IPI to every started hart but the current one
+pub fn send_others(ipi: Ipi) {
+    let mut mask = hart::online_mask();
+    mask.clear(thread_pointer());
+    send_mask(mask, ipi);
+}
+
+/// Handle the IPIs pending on the current hart
+///
+/// Called on a supervisor software interrupt.
+pub fn handle_ipi() {
+    let pending = PENDING[thread_pointer()].swap(0, Ordering::Acquire);
+
+    if pending & Ipi::CallFunction.bit() != 0 {
+        smp::call_function_interrupt();
+    }
+    if pending & Ipi::Watchdog.bit() != 0 {
+        watchdog::ping_interrupt();
+    }
+    if pending & Ipi::Timer.bit() != 0 {
+        tick::program();
+    }
+}
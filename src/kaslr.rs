@@ -0,0 +1,159 @@
+// Kernel address space layout randomisation (KASLR)
+//
+// The image is loaded at the physical address given in memory.x. With KASLR
+// it is also mapped at a random virtual address in the upper half of Sv39,
+// and S-mode runs from there. The image is built position independent, so
+// code works at any address, and the absolute addresses kept in its data are
+// patched at boot from the R_RISCV_RELATIVE entries in .rela.dyn.
+//
+// The identity mapping of the image stays: machine mode does not translate,
+// and pointers taken before the switch are physical.
+//
+// Booting with `nokaslr` keeps the kernel at its physical address.
+
+use crate::arch;
+use crate::cmdline;
+use crate::fdt;
+use crate::mem;
+use crate::rand::{self, xorshift::XorShift};
+use crate::symbols::{RELA_END, RELA_START, TEXT_START};
+
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{info, warn};
+
+/// Start of the upper half of the Sv39 address space
+const KASLR_BASE: usize = 0xffff_ffc0_0000_0000;
+/// The image is placed at a multiple of a megapage
+const KASLR_ALIGN: usize = 2 << 20;
+/// Number of possible placements, a 64 GiB window
+const KASLR_SLOTS: usize = 1 << 15;
+
+/// Rounds of mtime jitter mixed into the seed
+const JITTER_ROUNDS: usize = 64;
+
+const R_RISCV_NONE: u64 = 0;
+const R_RISCV_RELATIVE: u64 = 3;
+
+/// Entry of .rela.dyn
+#[repr(C)]
+struct Rela {
+    offset: usize,
+    info: u64,
+    addend: isize,
+}
+
+/// Virtual offset of the kernel, 0 if it runs at its physical address
+static OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+pub fn offset() -> usize {
+    OFFSET.load(Ordering::Relaxed)
+}
+
+/// The address in the linked image of the kernel address `addr`
+///
+/// The image is linked where it is loaded, so this is also the physical
+/// address. Addresses below `KASLR_BASE` are physical already, such as
+/// those of code running in machine mode, and are returned as is.
+pub fn link_address(addr: usize) -> usize {
+    if addr >= KASLR_BASE {
+        addr.wrapping_sub(offset())
+    } else {
+        addr
+    }
+}
+
+/// Count spins until mtime ticks over
+///
+/// The count depends on what the host does, which makes it a weak but
+/// free source of entropy.
+fn jitter() -> u64 {
+    let start = arch::riscv::time();
+    let mut spins = 0u64;
+    while arch::riscv::time() == start {
+        spins += 1;
+    }
+    spins
+}
+
+/// Seed from the device tree, mtime and mtime jitter
+fn seed() -> u32 {
+    let mut seed = rand::mix(0, arch::riscv::time() as u64);
+
+    let chosen = fdt::fdt().and_then(|fdt| fdt.find_node("/chosen"));
+    for name in ["kaslr-seed", "rng-seed"] {
+        if let Some(property) = chosen.and_then(|chosen| chosen.property(name)) {
+            for chunk in property.value().chunks(8) {
+                let mut bytes = [0u8; 8];
+                bytes[..chunk.len()].copy_from_slice(chunk);
+                seed = rand::mix(seed, u64::from_le_bytes(bytes));
+            }
+        }
+    }
+
+    for _ in 0..JITTER_ROUNDS {
+        seed = rand::mix(seed, jitter());
+    }
+
+    // xorshift never leaves a zero state
+    (seed ^ seed >> 32) as u32 | 1
+}
+
+fn relocations() -> &'static [Rela] {
+    let len = (RELA_END() - RELA_START()) / size_of::<Rela>();
+    unsafe { core::slice::from_raw_parts(RELA_START() as *const Rela, len) }
+}
+
+/// Pick the offset of the kernel and map the image there
+///
+/// Returns the offset. Must run on the boot hart in machine mode, after the
+/// kernel page table is set up.
+pub unsafe fn init() -> usize {
+    if cmdline::has("nokaslr") {
+        info!("KASLR disabled");
+        return 0;
+    }
+
+    let unsupported = relocations()
+        .iter()
+        .filter(|rela| !matches!(rela.info, R_RISCV_NONE | R_RISCV_RELATIVE))
+        .count();
+    if unsupported != 0 {
+        warn!("KASLR disabled, {} unsupported relocations", unsupported);
+        return 0;
+    }
+
+    let mut rng = XorShift::from_seed(seed());
+    let slot = rng.next_u32() as usize % KASLR_SLOTS;
+    let offset = (KASLR_BASE + slot * KASLR_ALIGN).wrapping_sub(TEXT_START());
+
+    mem::map_kernel(offset);
+    OFFSET.store(offset, Ordering::Relaxed);
+    info!(
+        "KASLR: kernel at 0x{:X}, {} relocations",
+        TEXT_START().wrapping_add(offset),
+        relocations().len()
+    );
+
+    offset
+}
+
+/// Apply the relocations for the offset picked by `init`
+///
+/// Afterwards the pointers kept in the kernel data only work through the
+/// new mapping. Must be the last Rust code the boot hart runs in machine
+/// mode, and no other hart may run Rust code in machine mode concurrently.
+pub unsafe fn relocate() {
+    let offset = offset();
+    if offset == 0 {
+        return;
+    }
+
+    for rela in relocations() {
+        if rela.info == R_RISCV_RELATIVE {
+            let slot = rela.offset as *mut usize;
+            slot.write((rela.addend as usize).wrapping_add(offset));
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use crate::arch;
 use crate::println;
+use crate::time::{self, DateTime};
 
 use log::{LevelFilter, Metadata, Record, SetLoggerError};
 
@@ -17,14 +18,25 @@ impl log::Log for KernelLogger {
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        if let Some(realtime) = time::realtime() {
+            let now = DateTime::from(realtime);
+            println!(
+                "[{}.{:0>6}][{}] {}",
+                now,
+                now.nanosecond / 1000,
+                record.level(),
+                record.args()
+            );
+        } else {
             let uptime = arch::riscv::uptime();
-            let sec = uptime.as_secs();
-            let ms = uptime.as_micros();
             println!(
                 "[{:5}.{:0>6}][{}] {}",
-                sec,
-                ms,
+                uptime.as_secs(),
+                uptime.subsec_micros(),
                 record.level(),
                 record.args()
             );
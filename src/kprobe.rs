@@ -0,0 +1,570 @@
+// Kernel probes
+//
+// A probe replaces the instruction at a kernel text address with a
+// `c.ebreak`. A hart that hits it traps into `breakpoint`, which runs the
+// pre-handler of the probe with the trap frame, then the displaced
+// instruction, and resumes behind it. The `c.ebreak` is a single halfword
+// store, even over a 4 byte instruction, so a hart running the code while
+// the probe goes in sees either the old or the new instruction.
+//
+// Jumps, branches and auipc depend on where they run and are emulated on
+// the trap frame. Other instructions are single-stepped out of line: each
+// probe has a slot in text with a copy of the instruction followed by a
+// `c.ebreak`. The hart returns into the slot with interrupts off, and the
+// second breakpoint sends it on behind the probed instruction.
+//
+// System instructions and instructions with an exception table entry are
+// not probed, neither would work from the slot.
+
+use crate::arch::riscv::thread_pointer;
+use crate::cmdline;
+use crate::extable;
+use crate::hart::MAX_HARTS;
+use crate::ksyms;
+use crate::patch;
+use crate::println;
+use crate::symbols::symbol;
+use crate::trap::{self, reg, Trap, TrapFrame};
+
+use core::arch::global_asm;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+
+use log::{info, warn};
+use spin::Mutex;
+
+/// Number of probes that can be registered at a time
+const MAX_KPROBES: usize = 16;
+/// Size of an out of line slot, an instruction and a `c.ebreak`
+const SLOT_SIZE: usize = 8;
+
+const C_EBREAK: u16 = 0x9002;
+
+const SSTATUS_SPIE: usize = 1 << 5;
+
+const NO_PROBE: usize = usize::MAX;
+
+/// Called when a hart hits the probe, before the probed instruction runs
+///
+/// Runs in trap context with interrupts disabled. Changing `sepc` skips
+/// the probed instruction and resumes there instead.
+pub type PreHandler = fn(&Kprobe, &mut TrapFrame);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Error {
+    /// The symbol is not in the symbol table
+    NoSymbol,
+    /// The address is not in kernel text
+    NotText,
+    /// Another probe is registered at the address
+    Exists,
+    /// The probe is registered already
+    Registered,
+    /// All probes are in use
+    NoSlot,
+    /// The instruction can not be single-stepped or emulated
+    Unsupported,
+    /// The instruction has an exception table entry
+    Fixup,
+}
+
+impl From<patch::Error> for Error {
+    fn from(_: patch::Error) -> Self {
+        Error::NotText
+    }
+}
+
+pub struct Kprobe {
+    symbol: Option<&'static str>,
+    offset: usize,
+    pre_handler: PreHandler,
+    /// Probed address, 0 when not registered
+    addr: AtomicUsize,
+    /// The displaced instruction
+    insn: AtomicU32,
+    hits: AtomicUsize,
+    missed: AtomicUsize,
+}
+
+impl Kprobe {
+    /// Probe `offset` bytes into the function `symbol`
+    pub const fn new(symbol: &'static str, offset: usize, pre_handler: PreHandler) -> Self {
+        Self {
+            symbol: Some(symbol),
+            offset,
+            pre_handler,
+            addr: AtomicUsize::new(0),
+            insn: AtomicU32::new(0),
+            hits: AtomicUsize::new(0),
+            missed: AtomicUsize::new(0),
+        }
+    }
+
+    /// Probe the kernel address `addr`
+    pub const fn at(addr: usize, pre_handler: PreHandler) -> Self {
+        Self {
+            symbol: None,
+            offset: addr,
+            pre_handler,
+            addr: AtomicUsize::new(0),
+            insn: AtomicU32::new(0),
+            hits: AtomicUsize::new(0),
+            missed: AtomicUsize::new(0),
+        }
+    }
+
+    /// The probed address, 0 if not registered
+    pub fn addr(&self) -> usize {
+        self.addr.load(Ordering::Acquire)
+    }
+
+    /// Times a hart hit the probe
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Hits that did not run the pre-handler, as they came from within one
+    pub fn missed(&self) -> usize {
+        self.missed.load(Ordering::Relaxed)
+    }
+
+    fn insn(&self) -> usize {
+        self.insn.load(Ordering::Relaxed) as usize
+    }
+}
+
+const EMPTY: AtomicPtr<Kprobe> = AtomicPtr::new(ptr::null_mut());
+
+/// Registered probes, the index is also the slot of the probe
+static PROBES: [AtomicPtr<Kprobe>; MAX_KPROBES] = [EMPTY; MAX_KPROBES];
+
+/// Serializes registering and unregistering
+static REGISTER: Mutex<()> = Mutex::new(());
+
+const IDLE: AtomicUsize = AtomicUsize::new(NO_PROBE);
+const CLEAR: AtomicBool = AtomicBool::new(false);
+
+/// Probe whose instruction the hart is stepping out of line
+static STEPPING: [AtomicUsize; MAX_HARTS] = [IDLE; MAX_HARTS];
+/// sstatus.SPIE of the probed code while stepping
+static SAVED_SPIE: [AtomicBool; MAX_HARTS] = [CLEAR; MAX_HARTS];
+/// Set while the hart runs a pre-handler
+static IN_HANDLER: [AtomicBool; MAX_HARTS] = [CLEAR; MAX_HARTS];
+
+// The out of line slots, MAX_KPROBES * SLOT_SIZE bytes. Written through
+// `patch::text_poke` like any other text.
+global_asm!(
+    r#"
+.pushsection .text.kprobe_slots, "ax", @progbits
+.balign 8
+.global __kprobe_slots
+__kprobe_slots:
+    .fill 128, 1, 0
+.popsection
+"#
+);
+
+fn slot(index: usize) -> usize {
+    symbol!("__kprobe_slots") + index * SLOT_SIZE
+}
+
+fn is_slot(addr: usize) -> bool {
+    (slot(0)..slot(MAX_KPROBES)).contains(&addr)
+}
+
+fn probe(index: usize) -> Option<&'static Kprobe> {
+    unsafe { PROBES[index].load(Ordering::Acquire).as_ref() }
+}
+
+/// The probe registered at `addr`, with its index
+fn find(addr: usize) -> Option<(usize, &'static Kprobe)> {
+    (0..MAX_KPROBES)
+        .filter_map(|index| Some((index, probe(index)?)))
+        .find(|(_, probe)| probe.addr() == addr)
+}
+
+const fn bits(insn: usize, hi: usize, lo: usize) -> usize {
+    (insn >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+/// Sign extend the low `width` bits of `value`
+const fn sext(value: usize, width: usize) -> usize {
+    let shift = usize::BITS as usize - width;
+    (((value << shift) as isize) >> shift) as usize
+}
+
+const fn insn_len(insn: usize) -> usize {
+    if insn & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+/// Read the instruction at the kernel text address `addr`
+fn fetch(addr: usize) -> usize {
+    let low = unsafe { ptr::read_volatile(addr as *const u16) } as usize;
+    if insn_len(low) == 2 {
+        return low;
+    }
+    let high = unsafe { ptr::read_volatile((addr + 2) as *const u16) } as usize;
+    low | high << 16
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Step {
+    /// Emulated on the trap frame
+    Emulate,
+    /// Run from the slot of the probe
+    OutOfLine,
+}
+
+/// How to run the displaced instruction `insn`, None if it can not be
+fn classify(insn: usize) -> Option<Step> {
+    if insn_len(insn) == 4 {
+        return match insn & 0x7f {
+            // AUIPC, JAL, JALR, BRANCH
+            0x17 | 0x6f | 0x67 | 0x63 => Some(Step::Emulate),
+            // SYSTEM: the CSR accesses as well, the slot runs with
+            // interrupts off and `sstatus` would be restored over them
+            0x73 => None,
+            _ => Some(Step::OutOfLine),
+        };
+    }
+
+    match (insn & 0b11, bits(insn, 15, 13)) {
+        // C.UNIMP
+        _ if insn == 0 => None,
+        // C.J, C.BEQZ, C.BNEZ
+        (0b01, 0b101) | (0b01, 0b110) | (0b01, 0b111) => Some(Step::Emulate),
+        // C.JR, C.JALR, C.EBREAK
+        (0b10, 0b100) if bits(insn, 6, 2) == 0 => match bits(insn, 11, 7) {
+            0 => None,
+            _ => Some(Step::Emulate),
+        },
+        _ => Some(Step::OutOfLine),
+    }
+}
+
+/// Offset of a JAL
+const fn imm_j(insn: usize) -> usize {
+    let imm = bits(insn, 31, 31) << 20
+        | bits(insn, 30, 21) << 1
+        | bits(insn, 20, 20) << 11
+        | bits(insn, 19, 12) << 12;
+    sext(imm, 21)
+}
+
+/// Offset of a branch
+const fn imm_b(insn: usize) -> usize {
+    let imm = bits(insn, 31, 31) << 12
+        | bits(insn, 30, 25) << 5
+        | bits(insn, 11, 8) << 1
+        | bits(insn, 7, 7) << 11;
+    sext(imm, 13)
+}
+
+/// Offset of a C.J
+const fn imm_cj(insn: usize) -> usize {
+    let imm = bits(insn, 12, 12) << 11
+        | bits(insn, 11, 11) << 4
+        | bits(insn, 10, 9) << 8
+        | bits(insn, 8, 8) << 10
+        | bits(insn, 7, 7) << 6
+        | bits(insn, 6, 6) << 7
+        | bits(insn, 5, 3) << 1
+        | bits(insn, 2, 2) << 5;
+    sext(imm, 12)
+}
+
+/// Offset of a C.BEQZ or C.BNEZ
+const fn imm_cb(insn: usize) -> usize {
+    let imm = bits(insn, 12, 12) << 8
+        | bits(insn, 11, 10) << 3
+        | bits(insn, 6, 5) << 6
+        | bits(insn, 4, 3) << 1
+        | bits(insn, 2, 2) << 5;
+    sext(imm, 9)
+}
+
+/// Run the instruction `insn` at sepc on the trap frame
+fn emulate(insn: usize, frame: &mut TrapFrame) {
+    let pc = frame.sepc;
+    let next = pc + insn_len(insn);
+
+    frame.sepc = if insn_len(insn) == 2 {
+        let rs1 = bits(insn, 11, 7);
+        // rs1' of C.BEQZ and C.BNEZ names x8-x15
+        let rs1_prime = frame.reg(bits(insn, 9, 7) + 8);
+        match (insn & 0b11, bits(insn, 15, 13)) {
+            (0b01, 0b101) => pc.wrapping_add(imm_cj(insn)),
+            (0b01, 0b110) if rs1_prime == 0 => pc.wrapping_add(imm_cb(insn)),
+            (0b01, 0b111) if rs1_prime != 0 => pc.wrapping_add(imm_cb(insn)),
+            (0b01, _) => next,
+            _ => {
+                // C.JR and C.JALR, read rs1 before ra is written
+                let target = frame.reg(rs1) & !1;
+                if bits(insn, 12, 12) == 1 {
+                    frame.set_reg(reg::RA, next);
+                }
+                target
+            }
+        }
+    } else {
+        let rd = bits(insn, 11, 7);
+        let rs1 = frame.reg(bits(insn, 19, 15));
+        let rs2 = frame.reg(bits(insn, 24, 20));
+        match insn & 0x7f {
+            0x17 => {
+                frame.set_reg(rd, pc.wrapping_add(sext(insn & 0xffff_f000, 32)));
+                next
+            }
+            0x6f => {
+                frame.set_reg(rd, next);
+                pc.wrapping_add(imm_j(insn))
+            }
+            0x67 => {
+                frame.set_reg(rd, next);
+                rs1.wrapping_add(sext(bits(insn, 31, 20), 12)) & !1
+            }
+            _ => {
+                let taken = match bits(insn, 14, 12) {
+                    0 => rs1 == rs2,
+                    1 => rs1 != rs2,
+                    4 => (rs1 as isize) < (rs2 as isize),
+                    5 => (rs1 as isize) >= (rs2 as isize),
+                    6 => rs1 < rs2,
+                    _ => rs1 >= rs2,
+                };
+                if taken {
+                    pc.wrapping_add(imm_b(insn))
+                } else {
+                    next
+                }
+            }
+        }
+    };
+}
+
+/// Trap handler for breakpoints
+fn breakpoint(frame: &mut TrapFrame) -> bool {
+    if !frame.from_kernel() {
+        return false;
+    }
+    let hart = thread_pointer();
+
+    // Done stepping out of line
+    let stepping = STEPPING[hart].load(Ordering::Relaxed);
+    if let Some(probe) = (stepping != NO_PROBE).then(|| probe(stepping)).flatten() {
+        let len = insn_len(probe.insn());
+        if frame.sepc == slot(stepping) + len {
+            STEPPING[hart].store(NO_PROBE, Ordering::Relaxed);
+            if SAVED_SPIE[hart].load(Ordering::Relaxed) {
+                frame.sstatus |= SSTATUS_SPIE;
+            }
+            frame.sepc = probe.addr() + len;
+            return true;
+        }
+    }
+
+    let (index, probe) = match find(frame.sepc) {
+        Some(found) => found,
+        None => return false,
+    };
+    probe.hits.fetch_add(1, Ordering::Relaxed);
+
+    if IN_HANDLER[hart].swap(true, Ordering::Acquire) {
+        // Hit from within a pre-handler, only run the instruction
+        probe.missed.fetch_add(1, Ordering::Relaxed);
+    } else {
+        (probe.pre_handler)(probe, frame);
+        IN_HANDLER[hart].store(false, Ordering::Release);
+        if frame.sepc != probe.addr() {
+            return true;
+        }
+    }
+
+    let insn = probe.insn();
+    match classify(insn) {
+        Some(Step::Emulate) => emulate(insn, frame),
+        _ => {
+            // Nothing may hit a probe on this hart until the slot is done
+            SAVED_SPIE[hart].store(frame.sstatus & SSTATUS_SPIE != 0, Ordering::Relaxed);
+            frame.sstatus &= !SSTATUS_SPIE;
+            STEPPING[hart].store(index, Ordering::Relaxed);
+            frame.sepc = slot(index);
+        }
+    }
+    true
+}
+
+/// Plant `probe`
+///
+/// Patches kernel text, must be called with interrupts enabled.
+pub fn register_kprobe(probe: &'static Kprobe) -> Result<(), Error> {
+    let addr = match probe.symbol {
+        Some(name) => ksyms::address(name).ok_or(Error::NoSymbol)? + probe.offset,
+        None => probe.offset,
+    };
+    if addr % 2 != 0 || !patch::is_text(addr, 2) || is_slot(addr) {
+        return Err(Error::NotText);
+    }
+    // A fault in the slot would not find the fixup
+    if extable::search(addr).is_some() {
+        return Err(Error::Fixup);
+    }
+
+    let _guard = REGISTER.lock();
+    if probe.addr() != 0 {
+        return Err(Error::Registered);
+    }
+    if find(addr).is_some() {
+        return Err(Error::Exists);
+    }
+    let index = PROBES
+        .iter()
+        .position(|probe| probe.load(Ordering::Relaxed).is_null())
+        .ok_or(Error::NoSlot)?;
+
+    let insn = fetch(addr);
+    let len = insn_len(insn);
+    match classify(insn).ok_or(Error::Unsupported)? {
+        Step::Emulate => {}
+        Step::OutOfLine => {
+            let mut code = [0u8; SLOT_SIZE];
+            code[..4].copy_from_slice(&(insn as u32).to_le_bytes());
+            code[len..len + 2].copy_from_slice(&C_EBREAK.to_le_bytes());
+            patch::text_poke(slot(index), &code[..len + 2])?;
+        }
+    }
+
+    probe.insn.store(insn as u32, Ordering::Relaxed);
+    probe.addr.store(addr, Ordering::Release);
+    PROBES[index].store(probe as *const Kprobe as *mut Kprobe, Ordering::Release);
+
+    patch::text_poke(addr, &C_EBREAK.to_le_bytes())?;
+    info!("kprobe: planted at 0x{:x}", addr);
+    Ok(())
+}
+
+/// Remove `probe`, the pre-handler is not running anywhere on return
+pub fn unregister_kprobe(probe: &'static Kprobe) {
+    let _guard = REGISTER.lock();
+    let addr = probe.addr();
+    let index = match find(addr) {
+        Some((index, found)) if ptr::eq(found, probe) => index,
+        _ => return,
+    };
+
+    // Only the first halfword was replaced. Waits for every hart to pass
+    // through a point with interrupts enabled, so none is in the handler
+    // or stepping the slot any more.
+    let insn = (probe.insn() as u32).to_le_bytes();
+    if let Err(err) = patch::text_poke(addr, &insn[..2]) {
+        warn!("kprobe: failed to restore 0x{:x}: {:?}", addr, err);
+        return;
+    }
+
+    PROBES[index].store(ptr::null_mut(), Ordering::Release);
+    probe.addr.store(0, Ordering::Release);
+    info!("kprobe: removed from 0x{:x}", addr);
+}
+
+/// Print the registered probes
+pub fn dump() {
+    println!("{:<18} {:>10} {:>10} symbol", "address", "hits", "missed");
+    for probe in (0..MAX_KPROBES).filter_map(probe) {
+        let addr = probe.addr();
+        match ksyms::lookup(addr) {
+            Some((name, offset)) => println!(
+                "0x{:016x} {:>10} {:>10} {}+0x{:x}",
+                addr,
+                probe.hits(),
+                probe.missed(),
+                name,
+                offset
+            ),
+            None => println!(
+                "0x{:016x} {:>10} {:>10}",
+                addr,
+                probe.hits(),
+                probe.missed()
+            ),
+        }
+    }
+}
+
+/// Take over the breakpoint exception
+pub fn init() {
+    if trap::register_handler(Trap::Breakpoint, breakpoint).is_err() {
+        warn!("kprobe: {} already claimed", Trap::Breakpoint);
+    }
+}
+
+extern "C" {
+    /// Returns `x + 1`, or 2 for 0, probed by `test`
+    fn __kprobe_test(x: usize) -> usize;
+}
+
+global_asm!(
+    r#"
+# Full size instructions, the probes of `test` go at fixed offsets. The
+# branch is emulated, the addi stepped out of line.
+.global __kprobe_test
+.align 4
+.option push
+.option norvc
+__kprobe_test:
+    beq a0, zero, 1f
+    addi a0, a0, 1
+    ret
+1:
+    addi a0, a0, 2
+    ret
+.option pop
+"#
+);
+
+fn test_handler(_: &Kprobe, _: &mut TrapFrame) {}
+
+static TEST_BRANCH: Kprobe = Kprobe::new("__kprobe_test", 0, test_handler);
+static TEST_STEP: Kprobe = Kprobe::new("__kprobe_test", 4, test_handler);
+
+/// Probe a test function and check it still works, with `kprobe_test`
+///
+/// The function is called with the branch against x0 taken and not taken,
+/// which also steps the instruction behind the branch. Patches text, must
+/// be called with interrupts enabled.
+pub fn test() {
+    if !cmdline::has("kprobe_test") {
+        return;
+    }
+    if !ksyms::is_present() {
+        warn!("kprobe_test: no symbol table, boot through scripts/run.sh");
+        return;
+    }
+
+    let probes = [&TEST_BRANCH, &TEST_STEP];
+    let passed = match probes.iter().try_for_each(|probe| register_kprobe(probe)) {
+        Ok(()) => {
+            let results = unsafe { [__kprobe_test(0), __kprobe_test(5)] };
+            let hits = [TEST_BRANCH.hits(), TEST_STEP.hits()];
+            let passed = results == [2, 6] && hits == [2, 1];
+            if !passed {
+                warn!(
+                    "kprobe_test: returned {:?}, expected [2, 6], hits {:?}, expected [2, 1]",
+                    results, hits
+                );
+            }
+            passed
+        }
+        Err(err) => {
+            warn!("kprobe_test: {:?}", err);
+            false
+        }
+    };
+    for probe in probes {
+        unregister_kprobe(probe);
+    }
+    info!("kprobe_test: {}", if passed { "passed" } else { "FAILED" });
+}
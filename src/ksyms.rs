@@ -0,0 +1,65 @@
+// Kernel symbol table
+//
+// The .ksyms section is reserved here and filled in after linking by
+// scripts/ksyms.sh, which the cargo runner calls. It holds the text symbols
+// as nm lists them, sorted by address:
+//
+//   0000000080000000 T _start
+//
+// and is NUL padded. A kernel that was not run through the script has an
+// empty table, and backtraces show bare addresses.
+
+use crate::kaslr;
+use crate::symbols::{KSYMS_END, KSYMS_START};
+
+/// Room for the table, scripts/ksyms.sh fails if it does not fit
+const KSYMS_SIZE: usize = 0x40000;
+
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+/// The text of the table, read through the linker symbols so that the
+/// compiler can not assume it is all zeros
+fn table() -> &'static [u8] {
+    let bytes = unsafe {
+        core::slice::from_raw_parts(KSYMS_START() as *const u8, KSYMS_END() - KSYMS_START())
+    };
+    let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+    &bytes[..len]
+}
+
+/// Parse a line of the table into its address and name
+fn parse(line: &[u8]) -> Option<(usize, &str)> {
+    let line = core::str::from_utf8(line).ok()?;
+    let (addr, rest) = line.split_once(' ')?;
+    let (_kind, name) = rest.split_once(' ')?;
+    Some((usize::from_str_radix(addr, 16).ok()?, name))
+}
+
+/// Look up the symbol containing the kernel address `addr`
+///
+/// Returns the name of the symbol and the offset of `addr` into it.
+pub fn lookup(addr: usize) -> Option<(&'static str, usize)> {
+    let addr = kaslr::link_address(addr);
+    table()
+        .split(|&c| c == b'\n')
+        .filter_map(parse)
+        .take_while(|&(start, _)| start <= addr)
+        .last()
+        .map(|(start, name)| (name, addr - start))
+}
+
+/// Address of the symbol `name` in the running kernel
+pub fn address(name: &str) -> Option<usize> {
+    table()
+        .split(|&c| c == b'\n')
+        .filter_map(parse)
+        .find(|&(_, symbol)| symbol == name)
+        .map(|(addr, _)| addr.wrapping_add(kaslr::offset()))
+}
+
+/// Check if the symbol table was filled in
+pub fn is_present() -> bool {
+    !table().is_empty()
+}
@@ -3,27 +3,71 @@
 #![feature(sync_unsafe_cell)]
 
 pub mod arch;
+pub mod backtrace;
 pub mod clint;
+pub mod clocksource;
+pub mod cmdline;
+pub mod console;
+pub mod extable;
+pub mod fbcon;
+pub mod fdt;
+pub mod font;
+pub mod fw_cfg;
+pub mod hart;
 pub mod interrupt;
+pub mod ipi;
+pub mod kaslr;
 pub mod klog;
+pub mod kprobe;
+pub mod ksyms;
 pub mod mem;
+pub mod memlog;
+pub mod misaligned;
 pub mod page;
+pub mod patch;
 pub mod plic;
+pub mod pmp;
 pub mod rand;
+pub mod rtc;
+pub mod sbi;
+pub mod smp;
+pub mod softirq;
+pub mod sstc;
+pub mod stats;
 pub mod symbols;
+pub mod tick;
+pub mod time;
+pub mod timer;
 pub mod trap;
+pub mod uaccess;
 pub mod uart;
+pub mod watchdog;
+pub mod workqueue;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const NOT_PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Harts in the panic handler, a panic while printing the backtrace skips it
+static PANICKING: [AtomicBool; hart::MAX_HARTS] = [NOT_PANICKING; hart::MAX_HARTS];
 
 /// Panic handler
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    println!("hart {} aborting: ", arch::riscv::thread_pointer());
+    let hart = arch::riscv::thread_pointer();
+    console::force_unlock();
+    println!("hart {} aborting: ", hart);
     if let Some(p) = info.location() {
         println!("line {}, file {}: {}", p.line(), p.file(), info.message());
     } else {
         println!("no information available.");
     }
 
+    if !PANICKING[hart].swap(true, Ordering::Relaxed) {
+        backtrace::backtrace();
+    }
+    console::flush();
+
     loop {
         riscv::asm::wfi();
     }
@@ -2,38 +2,66 @@
 #![no_main]
 
 use core::arch::global_asm;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
 
 use rost::arch;
 use rost::clint;
+use rost::clocksource;
+use rost::cmdline;
+use rost::console;
+use rost::fbcon;
+use rost::fdt;
+use rost::fw_cfg;
+use rost::hart;
+use rost::interrupt;
+use rost::kaslr;
 use rost::klog;
+use rost::kprobe;
 use rost::mem;
+use rost::misaligned;
 use rost::plic;
+use rost::pmp;
+use rost::rtc;
+use rost::softirq;
+use rost::sstc;
+use rost::tick;
 use rost::trap;
+use rost::uaccess;
 use rost::uart;
+use rost::watchdog;
+use rost::workqueue;
 
-use log::{info, LevelFilter};
+use log::{info, warn, LevelFilter};
 
 use riscv::register::*;
 use riscv_rt::entry;
 
-/// Blocks harts > 0 from booting, gets set to true when hart 0
-/// is initated and interrupts are turned on
-static BOOT: AtomicBool = AtomicBool::new(false);
-
 extern "C" {
-    fn goto_supervised();
+    fn goto_supervised(satp: usize);
 }
 
+/// Set by hart 0 when the other harts may set themselves up in M-mode
+static MACHINE_INIT: AtomicBool = AtomicBool::new(false);
+/// Harts other than 0 that are done with their M-mode setup
+static MACHINE_READY: AtomicUsize = AtomicUsize::new(0);
+/// Set by hart 0 when the kernel has been relocated
+static RELOCATED: AtomicBool = AtomicBool::new(false);
+
+/// How long the boot hart waits for the other harts to park
+const PARK_TIMEOUT: Duration = Duration::from_millis(100);
+
 global_asm!(
     r#"
 .global goto_supervised
 .align 4
 goto_supervised:
-    csrw satp, zero # Disable paging
-    csrw pmpcfg0, 0xf
-    # Delegate interrupts to supervisor mode
-    li t0, 0xffff
+    # Paging is off when a0 is zero, else the kernel page table
+    csrw satp, a0
+    sfence.vma
+    # Delegate interrupts and exceptions to supervisor mode, but for
+    # environment calls from S-mode, which machine mode serves as SBI
+    li t0, 0xfdff
     csrw medeleg, t0
     li t0, 0xffff
     csrw mideleg, t0
@@ -49,67 +77,168 @@ goto_supervised:
 ///
 /// Go to supervised mode when initialization is done
 #[entry]
-unsafe fn kinit() -> ! {
+unsafe fn kinit(_hart: usize, dtb: usize, _: usize) -> ! {
     if mhartid::read() == 0 {
+        pmp::hartinit();
+        clint::hartinit();
+        sstc::hartinit();
         klog::init(LevelFilter::Trace).expect("Failed to setup logger");
         uart::Uart::new(uart::UART_BASE_ADDR).init();
+        fdt::init(dtb);
+        clocksource::init();
+        cmdline::init();
 
         info!("Booting Rost ...");
         info!("Current hart: {}", mhartid::read());
 
         mem::init();
         plic::init();
+        uart::init();
+        rtc::init();
+        fw_cfg::init();
         mem::enable_mmu();
         trap::hartinit();
         plic::hartinit();
         clint::timer_init();
-    }
+        pmp::dump();
+        plic::dump_routes();
+        kaslr::init();
+
+        // The other harts must be done in M-mode before the relocation
+        MACHINE_INIT.store(true, Ordering::Release);
+        wait_machine_ready();
+
+        info!("Jumping to supervisor mode");
+        // Nothing may touch the kernel data in M-mode after this
+        kaslr::relocate();
+        RELOCATED.store(true, Ordering::Release);
+    } else {
+        while !MACHINE_INIT.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        pmp::hartinit();
+        clint::hartinit();
+        sstc::hartinit();
+        MACHINE_READY.fetch_add(1, Ordering::Release);
 
-    info!("Jumping to supervisor mode");
+        while !RELOCATED.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+    }
 
+    let offset = kaslr::offset();
     mstatus::set_mpp(mstatus::MPP::Supervisor);
-    mepc::write(kmain as usize);
+    mepc::write((kmain as usize).wrapping_add(offset));
 
-    goto_supervised();
+    // Without KASLR S-mode starts with paging off, as before
+    goto_supervised(if offset == 0 { 0 } else { mem::satp() });
 
     loop {
         rost::arch::riscv::wait();
     }
 }
 
+/// Wait for the other harts in the device tree to finish their M-mode setup
+///
+/// Gives up after `PARK_TIMEOUT`, a hart that never came up can not hold up
+/// the boot.
+fn wait_machine_ready() {
+    let harts = fdt::fdt()
+        .and_then(|fdt| fdt.find_node("/cpus"))
+        .map_or(1, |cpus| {
+            cpus.children()
+                .filter(|node| node.name().starts_with("cpu@"))
+                .count()
+        });
+    let others = harts.saturating_sub(1);
+
+    let deadline = clocksource::Instant::now() + PARK_TIMEOUT;
+    while MACHINE_READY.load(Ordering::Acquire) < others {
+        if clocksource::Instant::now() >= deadline {
+            warn!(
+                "{} of {} harts set up machine mode",
+                MACHINE_READY.load(Ordering::Acquire),
+                others
+            );
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
 /// Kernel main
 /// Never returns.
 #[no_mangle]
 unsafe fn kmain() -> ! {
-    info!("Initiating hart:{}", arch::riscv::thread_pointer());
-    if arch::riscv::thread_pointer() == 0 {
-        // Release the other HARTs
-        // BOOT.store(true, Ordering::Relaxed);
-    } else {
-        while !BOOT.load(Ordering::Relaxed) {
-            rost::arch::riscv::wait();
-        }
-        hartinit();
+    let hart = arch::riscv::thread_pointer();
+    info!("Initiating hart:{}", hart);
+    if hart != 0 {
+        // Wait for hart 0 to start us, continues in hart_main
+        hart::park();
     }
 
+    hart::boot();
+    console::init();
+    fbcon::init();
+    softirq::init();
+    interrupt::init();
+    misaligned::init();
+    kprobe::init();
+    pmp::test();
+    uaccess::test();
+    sstc::init();
+    tick::hartinit();
+    watchdog::init();
+    watchdog::hartinit();
     trap::enable_interrupts();
 
-    info!("hart #{} ready", arch::riscv::thread_pointer());
+    info!("hart #{} ready", hart);
     clint::debug();
 
-    loop {
-        rost::arch::riscv::wait();
+    // Release the other HARTs, once they are parked. Harts that do not
+    // show up by the deadline are taken to not exist.
+    let deadline = clocksource::Instant::now() + PARK_TIMEOUT;
+    for id in (0..hart::MAX_HARTS).filter(|&id| id != hart) {
+        if !hart::wait_present(id, deadline) {
+            continue;
+        }
+        match hart::start(id, hart_main) {
+            Ok(()) => info!("Starting hart {}", id),
+            Err(err) => warn!("hart {} not started, {:?}", id, err),
+        }
     }
+
+    kprobe::test();
+    hart::test(hart_main);
+
+    workqueue::worker()
+}
+
+/// Main of the secondary harts
+///
+/// Entered through `hart::start`, both at boot and when a stopped hart
+/// is started again.
+fn hart_main() -> ! {
+    hartinit();
+    unsafe {
+        trap::enable_interrupts();
+    }
+
+    info!("hart #{} ready", arch::riscv::thread_pointer());
+    hart::test_stop();
+
+    workqueue::worker()
 }
 
 /// Initiate a hart
 ///
 /// Called for each hart that is starting up.
-/// Calls kmain
 fn hartinit() {
     info!("Booting hart {}", arch::riscv::thread_pointer());
     mem::enable_mmu();
     plic::hartinit();
+    tick::hartinit();
+    watchdog::hartinit();
     unsafe {
         trap::hartinit();
     }
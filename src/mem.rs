@@ -1,7 +1,11 @@
 use crate::arch;
 use crate::clint::{CLINT_BASE, CLINT_SIZE};
+use crate::fdt;
+use crate::fw_cfg::{FW_CFG_BASE, FW_CFG_SIZE};
+use crate::kaslr;
 use crate::page::{self, Attribute, PageTable, KERNEL_PAGE_TABLE};
-use crate::plic::PLIC_BASE;
+use crate::plic::{PLIC_BASE, PLIC_SIZE};
+use crate::rtc::{RTC_BASE, RTC_SIZE};
 use crate::symbols::*;
 use crate::uart;
 
@@ -47,14 +51,10 @@ impl Region {
     }
 }
 
-pub unsafe fn init() {
-    info!("Initiating memory");
-    page::init();
-
-    let pgtable: &mut PageTable = KERNEL_PAGE_TABLE.get_mut();
-
-    let regions = [
-        Region::new(DATA_START(), DATA_END(), Attribute::ReadExecute, "DATA"),
+/// The regions of the kernel image
+fn image_regions() -> [Region; 3] {
+    [
+        Region::new(DATA_START(), DATA_END(), Attribute::ReadWrite, "DATA"),
         //Region::new(
         //    RODATA_START(),
         //    RODATA_END(),
@@ -63,22 +63,34 @@ pub unsafe fn init() {
         //),
         Region::new(TEXT_START(), RODATA_START(), Attribute::ReadExecute, "Text"),
         Region::new(BSS_START(), BSS_END(), Attribute::ReadWrite, "BSS"),
+    ]
+}
+
+pub unsafe fn init() {
+    info!("Initiating memory");
+    page::init();
+
+    let pgtable: &mut PageTable = KERNEL_PAGE_TABLE.get_mut();
+
+    let regions = [
         Region::new(
             KERNEL_STACK_END(),
             KERNEL_STACK_START(),
             Attribute::ReadWrite,
             "KERNEL_STACK",
         ),
-        // Region::new(HEAP_START(), HEAP_END(), Attribute::ReadWrite, "Heap"),
+        // Page tables and allocations are used through their physical
+        // addresses, also when S-mode runs with paging on
+        Region::new(HEAP_START(), HEAP_END(), Attribute::ReadWrite, "Heap"),
         Region::new(
             uart::UART_BASE_ADDR,
-            uart::UART_BASE_ADDR,
+            uart::UART_BASE_ADDR + 0x100,
             Attribute::ReadWrite,
             "Uart",
         ),
         Region::new(
             PLIC_BASE,
-            PLIC_BASE + 0x8_000, // NCPUS * 2 * 1000
+            PLIC_BASE + PLIC_SIZE,
             Attribute::ReadWrite,
             "PLIC_BASE",
         ),
@@ -88,19 +100,94 @@ pub unsafe fn init() {
             Attribute::ReadWrite,
             "CLINT",
         ),
+        Region::new(RTC_BASE, RTC_BASE + RTC_SIZE, Attribute::ReadWrite, "RTC"),
+        Region::new(
+            FW_CFG_BASE,
+            FW_CFG_BASE + FW_CFG_SIZE,
+            Attribute::ReadWrite,
+            "FW_CFG",
+        ),
     ];
 
     info!("Mapping the kernel");
+    pgtable.id_map_ranges(image_regions().iter());
     pgtable.id_map_ranges(regions.iter());
+
+    if let Some(fdt) = fdt::fdt() {
+        let region = Region::new(fdt.addr(), fdt.addr() + fdt.size(), Attribute::Read, "FDT");
+        pgtable.id_map_range(&region);
+    }
+}
+
+/// Map the kernel image a second time, `offset` bytes above where it is loaded
+///
+/// Used by KASLR, the identity mapping stays in place.
+pub unsafe fn map_kernel(offset: usize) {
+    let pgtable: &mut PageTable = KERNEL_PAGE_TABLE.get_mut();
+
+    info!("Mapping the kernel at offset 0x{:X}", offset);
+    for region in image_regions().iter() {
+        pgtable.map_range(
+            region.start_addr(),
+            page::align_val(region.end_addr(), 12),
+            region.start_addr().wrapping_add(offset),
+            region.flags() as usize,
+        );
+    }
+}
+
+/// Map the 4 KiB page at `paddr` to `vaddr` in the kernel page table
+pub unsafe fn map_page(vaddr: usize, paddr: usize, flags: Attribute) {
+    let pgtable: &mut PageTable = KERNEL_PAGE_TABLE.get_mut();
+    pgtable.kernel_map(vaddr, paddr, flags as usize);
+    riscv::asm::sfence_vma(0, vaddr);
+}
+
+/// Map the 4 KiB page at `paddr` to the user address `vaddr`
+///
+/// There are no user address spaces yet, user pages go in the kernel page
+/// table. Removed with `unmap_page`.
+pub unsafe fn map_user_page(vaddr: usize, paddr: usize, flags: Attribute) {
+    let pgtable: &mut PageTable = KERNEL_PAGE_TABLE.get_mut();
+    pgtable.map_range(paddr, paddr + page::PAGE_SIZE, vaddr, flags as usize);
+    riscv::asm::sfence_vma(0, vaddr);
+}
+
+/// Remove a mapping made by `map_page` or `map_user_page`
+pub unsafe fn unmap_page(vaddr: usize) {
+    let pgtable: &mut PageTable = KERNEL_PAGE_TABLE.get_mut();
+    pgtable.unmap_addr(vaddr);
+    riscv::asm::sfence_vma(0, vaddr);
+}
+
+/// Check if the page at `vaddr` can be accessed from user mode
+///
+/// `write` checks for write access, else for read access.
+pub fn user_page(vaddr: usize, write: bool) -> bool {
+    let pgtable = unsafe { &*KERNEL_PAGE_TABLE.get() };
+    match pgtable.leaf(vaddr) {
+        Some(entry) if entry.user() => {
+            if write {
+                entry.writable()
+            } else {
+                entry.readable()
+            }
+        }
+        _ => false,
+    }
+}
+
+/// satp value for the kernel page table
+pub fn satp() -> usize {
+    // The table is reached through the KASLR mapping once relocated
+    let root = unsafe { KERNEL_PAGE_TABLE.get() as *const _ as usize };
+    arch::riscv::build_satp(8, 0, kaslr::link_address(root))
 }
 
 pub fn enable_mmu() {
     info!("Enabling mmu");
     unsafe {
-        let root_ppn = KERNEL_PAGE_TABLE.get() as *const _ as usize;
-
-        let satp_val = arch::riscv::build_satp(8, 0, root_ppn);
-        asm!("csrw satp, {}", in(reg) satp_val);
+        asm!("csrw satp, {}", in(reg) satp());
         riscv::asm::sfence_vma(0, 0);
     }
 }
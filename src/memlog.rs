@@ -0,0 +1,68 @@
+// Console output kept in memory
+//
+// The last LOG_SIZE bytes written to the console stay in a ring, from the
+// first line of the boot on. Positions count every byte ever written, so a
+// reader can pick up where it left off and tell what it missed.
+
+use crate::arch::riscv::without_interrupts;
+use crate::console::Backend;
+
+use spin::Mutex;
+
+const LOG_SIZE: usize = 16 * 1024;
+
+/// Only locked with interrupts off, the console writes from interrupts
+struct Log {
+    data: [u8; LOG_SIZE],
+    /// Bytes written since boot
+    written: usize,
+}
+
+static LOG: Mutex<Log> = Mutex::new(Log {
+    data: [0; LOG_SIZE],
+    written: 0,
+});
+
+pub struct MemLog;
+
+pub static CONSOLE: MemLog = MemLog;
+
+impl Backend for MemLog {
+    fn name(&self) -> &'static str {
+        "memlog"
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        let mut log = LOG.lock();
+        for &c in bytes {
+            let at = log.written % LOG_SIZE;
+            log.data[at] = c;
+            log.written += 1;
+        }
+    }
+
+    unsafe fn force_unlock(&self) {
+        LOG.force_unlock();
+    }
+}
+
+/// Bytes written since boot, the position after the last one
+pub fn written() -> usize {
+    without_interrupts(|| LOG.lock().written)
+}
+
+/// Copy the log from position `from` into `buf`
+///
+/// Bytes that were overwritten already are skipped. Returns the position
+/// the copy started at and the number of bytes copied.
+pub fn read(from: usize, buf: &mut [u8]) -> (usize, usize) {
+    without_interrupts(|| {
+        let log = LOG.lock();
+        let from = from.max(log.written.saturating_sub(LOG_SIZE));
+        let len = buf.len().min(log.written.saturating_sub(from));
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = log.data[(from + i) % LOG_SIZE];
+        }
+        (from, len)
+    })
+}
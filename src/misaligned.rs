@@ -0,0 +1,249 @@
+// Emulation of misaligned loads and stores
+//
+// Harts are allowed to trap on misaligned accesses instead of performing
+// them. The faulting instruction is decoded, the access is done a byte at a
+// time and the instruction is skipped. stval holds the address that was
+// accessed, so only the width, the register and the sign extension are
+// taken from the instruction.
+
+use crate::ksyms;
+use crate::println;
+use crate::trap::{self, Trap, TrapFrame};
+use crate::uaccess;
+
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{debug, warn};
+use spin::Mutex;
+
+/// Number of instructions tracked in the hot spot table
+const HOT_SPOTS: usize = 16;
+
+static LOADS: AtomicUsize = AtomicUsize::new(0);
+static STORES: AtomicUsize = AtomicUsize::new(0);
+
+/// Instructions that needed emulation, with how many times
+static SPOTS: Mutex<[(usize, usize); HOT_SPOTS]> = Mutex::new([(0, 0); HOT_SPOTS]);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Kind {
+    Load { signed: bool },
+    Store,
+}
+
+/// A decoded load or store
+#[derive(Debug, Clone, Copy)]
+struct Access {
+    kind: Kind,
+    /// Width in bytes
+    width: usize,
+    /// rd for loads, rs2 for stores
+    reg: usize,
+    /// Length of the instruction in bytes
+    len: usize,
+}
+
+impl Access {
+    const fn load(width: usize, signed: bool, reg: usize, len: usize) -> Self {
+        Self {
+            kind: Kind::Load { signed },
+            width,
+            reg,
+            len,
+        }
+    }
+
+    const fn store(width: usize, reg: usize, len: usize) -> Self {
+        Self {
+            kind: Kind::Store,
+            width,
+            reg,
+            len,
+        }
+    }
+}
+
+const fn bits(insn: usize, hi: usize, lo: usize) -> usize {
+    (insn >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+/// Decode a 32 bit load or store
+fn decode(insn: usize) -> Option<Access> {
+    let funct3 = bits(insn, 14, 12);
+    match insn & 0x7f {
+        // LOAD
+        0x03 => {
+            let rd = bits(insn, 11, 7);
+            match funct3 {
+                1 => Some(Access::load(2, true, rd, 4)),
+                2 => Some(Access::load(4, true, rd, 4)),
+                3 => Some(Access::load(8, true, rd, 4)),
+                5 => Some(Access::load(2, false, rd, 4)),
+                6 => Some(Access::load(4, false, rd, 4)),
+                _ => None,
+            }
+        }
+        // STORE
+        0x23 => {
+            let rs2 = bits(insn, 24, 20);
+            match funct3 {
+                1 => Some(Access::store(2, rs2, 4)),
+                2 => Some(Access::store(4, rs2, 4)),
+                3 => Some(Access::store(8, rs2, 4)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Decode a compressed load or store
+fn decode_compressed(insn: usize) -> Option<Access> {
+    // rd' and rs2' of quadrant 0 name x8-x15
+    let rd_prime = bits(insn, 4, 2) + 8;
+    match (insn & 0b11, bits(insn, 15, 13)) {
+        // C.LW, C.LD
+        (0b00, 0b010) => Some(Access::load(4, true, rd_prime, 2)),
+        (0b00, 0b011) => Some(Access::load(8, true, rd_prime, 2)),
+        // C.SW, C.SD
+        (0b00, 0b110) => Some(Access::store(4, rd_prime, 2)),
+        (0b00, 0b111) => Some(Access::store(8, rd_prime, 2)),
+        // C.LWSP, C.LDSP
+        (0b10, 0b010) => Some(Access::load(4, true, bits(insn, 11, 7), 2)),
+        (0b10, 0b011) => Some(Access::load(8, true, bits(insn, 11, 7), 2)),
+        // C.SWSP, C.SDSP
+        (0b10, 0b110) => Some(Access::store(4, bits(insn, 6, 2), 2)),
+        (0b10, 0b111) => Some(Access::store(8, bits(insn, 6, 2), 2)),
+        _ => None,
+    }
+}
+
+/// Read the halfword of the instruction stream at `addr`
+fn fetch(frame: &TrapFrame, addr: usize) -> Option<usize> {
+    let half = if frame.from_kernel() {
+        unsafe { ptr::read_volatile(addr as *const u16) }
+    } else {
+        uaccess::get_user::<u16>(addr).ok()?
+    };
+    Some(half as usize)
+}
+
+/// Fetch and decode the instruction at sepc
+///
+/// Text is only 2 byte aligned with compressed instructions, so it is read
+/// a halfword at a time.
+fn decode_at(frame: &TrapFrame) -> Option<Access> {
+    let low = fetch(frame, frame.sepc)?;
+    if low & 0b11 != 0b11 {
+        return decode_compressed(low);
+    }
+    let high = fetch(frame, frame.sepc + 2)?;
+    decode(low | high << 16)
+}
+
+fn read(frame: &TrapFrame, addr: usize, bytes: &mut [u8]) -> bool {
+    if !frame.from_kernel() {
+        return uaccess::copy_from_user(bytes, addr).is_ok();
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = unsafe { ptr::read_volatile((addr + i) as *const u8) };
+    }
+    true
+}
+
+fn write(frame: &TrapFrame, addr: usize, bytes: &[u8]) -> bool {
+    if !frame.from_kernel() {
+        return uaccess::copy_to_user(addr, bytes).is_ok();
+    }
+    for (i, byte) in bytes.iter().enumerate() {
+        unsafe { ptr::write_volatile((addr + i) as *mut u8, *byte) };
+    }
+    true
+}
+
+fn record(pc: usize) {
+    let mut spots = SPOTS.lock();
+    if let Some(spot) = spots.iter_mut().find(|(addr, _)| *addr == pc) {
+        spot.1 += 1;
+    } else if let Some(spot) = spots.iter_mut().min_by_key(|(_, count)| *count) {
+        // Replaces the least seen one once the table is full
+        *spot = (pc, 1);
+    }
+}
+
+/// Trap handler for misaligned loads and stores
+fn emulate(frame: &mut TrapFrame) -> bool {
+    let access = match decode_at(frame) {
+        Some(access) => access,
+        None => return false,
+    };
+    let addr = frame.stval;
+    let mut bytes = [0u8; 8];
+    let bytes = &mut bytes[..access.width];
+
+    match access.kind {
+        Kind::Load { signed } => {
+            if !read(frame, addr, bytes) {
+                return false;
+            }
+            let mut value = [0u8; 8];
+            value[..access.width].copy_from_slice(bytes);
+            let mut value = u64::from_le_bytes(value) as usize;
+            if signed && access.width < 8 {
+                let shift = 64 - access.width * 8;
+                value = (((value << shift) as isize) >> shift) as usize;
+            }
+            frame.set_reg(access.reg, value);
+            LOADS.fetch_add(1, Ordering::Relaxed);
+        }
+        Kind::Store => {
+            let value = frame.reg(access.reg).to_le_bytes();
+            bytes.copy_from_slice(&value[..access.width]);
+            if !write(frame, addr, bytes) {
+                return false;
+            }
+            STORES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    debug!(
+        "misaligned: emulated {:?} of {} bytes at 0x{:x}, pc 0x{:x}",
+        access.kind, access.width, addr, frame.sepc
+    );
+    record(frame.sepc);
+    frame.sepc += access.len;
+    true
+}
+
+/// Number of emulated loads and stores
+pub fn counts() -> (usize, usize) {
+    (
+        LOADS.load(Ordering::Relaxed),
+        STORES.load(Ordering::Relaxed),
+    )
+}
+
+/// Print the instructions that needed emulation the most
+pub fn dump() {
+    let (loads, stores) = counts();
+    println!("Misaligned accesses: {} loads, {} stores", loads, stores);
+
+    let mut spots = *SPOTS.lock();
+    spots.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+    for &(pc, count) in spots.iter().filter(|(_, count)| *count > 0) {
+        match ksyms::lookup(pc) {
+            Some((name, offset)) => println!("{:>10} 0x{:016x} {}+0x{:x}", count, pc, name, offset),
+            None => println!("{:>10} 0x{:016x}", count, pc),
+        }
+    }
+}
+
+/// Take over the misaligned load and store exceptions
+pub fn init() {
+    for trap in [Trap::LoadAddressMisaligned, Trap::StoreAddressMisaligned] {
+        if trap::register_handler(trap, emulate).is_err() {
+            warn!("misaligned: {} already claimed", trap);
+        }
+    }
+}
@@ -14,6 +14,11 @@ const PAGE_TABLE_ENTRIES: usize = 512;
 
 static mut PAGES: usize = 0;
 static mut PAGE_ALLOC_START: usize = 0;
+/// Physical start of the heap, where the page descriptors are
+///
+/// `HEAP_START()` gives the KASLR address of the heap in S-mode, which is
+/// not mapped.
+static mut HEAP_BASE: usize = 0;
 
 pub static mut KERNEL_PAGE_TABLE: SyncUnsafeCell<PageTable> = SyncUnsafeCell::new(PageTable::new());
 
@@ -39,21 +44,24 @@ pub const fn align_page_down(val: usize) -> usize {
 pub fn init() {
     info!("Initiating paging");
     unsafe {
-        PAGES = HEAP_SIZE() / PAGE_SIZE;
-        let ptr = HEAP_START() as *mut Page;
-
+        HEAP_BASE = HEAP_START();
+        // One descriptor per page at the start of the heap, the pages
+        // handed out follow them
+        let descriptors = HEAP_SIZE() / PAGE_SIZE;
+        PAGE_ALLOC_START = align_val(HEAP_BASE + descriptors * size_of::<Page>(), PAGE_ORDER);
+        PAGES = (HEAP_BASE + HEAP_SIZE() - PAGE_ALLOC_START) / PAGE_SIZE;
+
+        let ptr = HEAP_BASE as *mut Page;
         for i in 0..PAGES {
             (*ptr.add(i)).clear();
         }
-
-        PAGE_ALLOC_START = align_val(HEAP_START() + PAGES + size_of::<Page>(), PAGE_ORDER);
     }
 }
 
 pub fn alloc(pages: usize) -> *mut u8 {
     assert!(pages > 0);
     unsafe {
-        let ptr = PAGE_ALLOC_START as *mut Page;
+        let ptr = HEAP_BASE as *mut Page;
         for i in 0..PAGES - pages {
             if !(*ptr.add(i)).is_free() {
                 continue;
@@ -89,8 +97,8 @@ pub fn dealloc(ptr: *mut u8) {
     assert!(!ptr.is_null());
 
     unsafe {
-        let addr = HEAP_START() + (ptr as usize - PAGE_ALLOC_START) / PAGE_SIZE;
-        assert!(addr >= HEAP_START() && addr < PAGE_ALLOC_START);
+        let addr = HEAP_BASE + (ptr as usize - PAGE_ALLOC_START) / PAGE_SIZE;
+        assert!(addr >= HEAP_BASE && addr < PAGE_ALLOC_START);
         let mut p = addr as *mut Page;
         assert!(!(*p).is_free());
         while !(*p).is_free() && !(*p).is_last() {
@@ -117,8 +125,8 @@ impl PageFlag {
     }
 }
 
+/// Descriptor of a page of the heap
 #[repr(C)]
-#[repr(align(4096))]
 pub struct Page {
     pub flag: u8,
 }
@@ -394,6 +402,37 @@ impl PageTable {
         }
     }
 
+    /// The leaf entry that maps `vaddr`, of any page size
+    pub fn leaf(&self, vaddr: usize) -> Option<Entry> {
+        let vpn = VPN(vaddr);
+        let mut table = self;
+        for lvl in (0..3).rev() {
+            let entry = table.entries[vpn.index(lvl)];
+            if !entry.valid() {
+                return None;
+            }
+            if entry.is_leaf() {
+                return Some(entry);
+            }
+            table = unsafe { &*(entry.physical_addr().0 as *const PageTable) };
+        }
+        None
+    }
+
+    /// Remove the 4 KiB mapping of `vaddr`, the tables stay in place
+    pub fn unmap_addr(&mut self, vaddr: usize) {
+        let vpn = VPN(vaddr);
+        let mut table = self;
+        for lvl in (1..3).rev() {
+            let entry = table.entries[vpn.index(lvl)];
+            if !entry.valid() || entry.is_leaf() {
+                return;
+            }
+            table = unsafe { &mut *(entry.physical_addr().0 as *mut PageTable) };
+        }
+        table.entries[vpn.index(0)] = Entry(0);
+    }
+
     pub fn phy_addr_of(&self, vaddr: usize) -> Option<usize> {
         assert!(
             vaddr % PAGE_SIZE == 0,
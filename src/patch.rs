@@ -0,0 +1,123 @@
+// Patching of kernel text
+//
+// Text is mapped read and execute only. To change it, the page is mapped a
+// second time, writable, at a fixed address, written through that alias and
+// unmapped again. Harts do not fetch the new instructions before they run
+// `fence.i`, so every started hart is made to run one.
+
+use crate::arch::riscv::fence_i;
+use crate::kaslr;
+use crate::mem;
+use crate::page::{Attribute, PAGE_SIZE};
+use crate::smp;
+use crate::symbols::{MACHINE_END, MACHINE_START, RODATA_START, TEXT_START};
+
+use core::ptr;
+
+use riscv::register::satp;
+use spin::Mutex;
+
+/// Where the writable alias is mapped, two pages at the top of Sv39, away
+/// from the identity mapping and the KASLR window
+const POKE_ADDR: usize = 0xffff_ffff_fff0_0000;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Error {
+    /// The range is not in kernel text
+    NotText,
+    /// Instructions are at least 2 byte aligned
+    Misaligned,
+}
+
+/// Serializes users of the alias
+static POKE: Mutex<()> = Mutex::new(());
+
+/// Check if `addr..addr + len` is in kernel text
+///
+/// The machine mode code is left out, the PMP keeps S-mode from writing it.
+pub fn is_text(addr: usize, len: usize) -> bool {
+    let end = addr.saturating_add(len);
+    addr >= TEXT_START()
+        && end <= RODATA_START()
+        && (end <= MACHINE_START() || addr >= MACHINE_END())
+}
+
+/// Write `bytes` to kernel text at `addr`
+///
+/// Aligned halfwords and words are written with a single store, so other
+/// harts see either the old or the new instruction, never a mix.
+///
+/// Sends an IPI to every hart, must be called with interrupts enabled.
+pub fn text_poke(addr: usize, bytes: &[u8]) -> Result<(), Error> {
+    if !is_text(addr, bytes.len()) {
+        return Err(Error::NotText);
+    }
+    if addr % 2 != 0 {
+        return Err(Error::Misaligned);
+    }
+
+    {
+        let _guard = POKE.lock();
+        // Text is loaded at its link address
+        let phys = kaslr::link_address(addr);
+        let page = phys & !(PAGE_SIZE - 1);
+        // At most two pages, the range may cross a page boundary
+        let pages = if (phys + bytes.len() - 1) / PAGE_SIZE != phys / PAGE_SIZE {
+            2
+        } else {
+            1
+        };
+        // With paging off, as on the boot hart with nokaslr, text is
+        // writable as it is
+        let paging = satp::read().bits() != 0;
+        let alias = if paging {
+            POKE_ADDR + (phys - page)
+        } else {
+            phys
+        };
+
+        unsafe {
+            if paging {
+                for i in 0..pages {
+                    mem::map_page(
+                        POKE_ADDR + i * PAGE_SIZE,
+                        page + i * PAGE_SIZE,
+                        Attribute::ReadWrite,
+                    );
+                }
+            }
+
+            match bytes.len() {
+                2 if alias % 2 == 0 => {
+                    ptr::write_volatile(alias as *mut u16, u16::from_le_bytes([bytes[0], bytes[1]]))
+                }
+                4 if alias % 4 == 0 => {
+                    let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                    ptr::write_volatile(alias as *mut u32, word)
+                }
+                _ => {
+                    for (i, byte) in bytes.iter().enumerate() {
+                        ptr::write_volatile((alias + i) as *mut u8, *byte);
+                    }
+                }
+            }
+
+            if paging {
+                for i in 0..pages {
+                    mem::unmap_page(POKE_ADDR + i * PAGE_SIZE);
+                }
+            }
+        }
+    }
+
+    sync_core();
+    Ok(())
+}
+
+/// Make every started hart fetch the current text
+///
+/// Returns when all of them ran `fence.i`. A hart handles the IPI with
+/// interrupts enabled, so it is also past any trap handler it was in.
+pub fn sync_core() {
+    smp::on_each_hart(fence_i, true);
+}
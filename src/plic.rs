@@ -2,26 +2,38 @@
 // IRQS in qemu:
 // VIRTIO: 1..8
 // UART0: 10
+// RTC: 11
 // PCIE: 32..35
 //
+// Each source has one global priority. Each hart context has its own
+// enable bits and threshold, an interrupt is routed to the harts whose
+// context has it enabled and the first one to claim it handles it.
+//
 
 use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::arch::riscv::thread_pointer;
+use crate::hart::{self, HartMask, MAX_HARTS};
 
 use log::info;
+use spin::Mutex;
 
 // PLIC mmio registers
 pub const PLIC_BASE: usize = 0x0c00_0000;
-const _PLIC_PRIORITY: usize = PLIC_BASE + 0x0;
+pub const PLIC_SIZE: usize = 0x400_0000;
+const PLIC_PRIORITY: usize = PLIC_BASE + 0x0;
 const PLIC_PENDING: usize = PLIC_BASE + 0x1000;
 const _PLIC_MENABLE_BASE: usize = PLIC_BASE + 0x2000;
 const PLIC_SENABLE_BASE: usize = PLIC_BASE + 0x2080;
-const _PLIC_MPRIORITY_BASE: usize = PLIC_BASE + 0x200000;
-const PLIC_SPRIORITY_BASE: usize = PLIC_BASE + 0x201000;
+const _PLIC_MTHRESHOLD_BASE: usize = PLIC_BASE + 0x200000;
+const PLIC_STHRESHOLD_BASE: usize = PLIC_BASE + 0x201000;
 const _PLIC_MCLAIM_BASE: usize = PLIC_BASE + 0x200004;
 const PLIC_SCLAIM_BASE: usize = PLIC_BASE + 0x201004;
 
+/// Number of interrupt sources on QEMU virt, including the reserved source 0
+pub const PLIC_SOURCES: usize = 96;
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Threshold {
     All,
@@ -31,7 +43,7 @@ pub enum Threshold {
 
 /// Interrupt priority.
 ///
-/// One equals the highest priority
+/// Seven equals the highest priority, Zero never interrupts
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Priority {
     Zero = 0,
@@ -44,6 +56,40 @@ pub enum Priority {
     Seven,
 }
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Error {
+    /// Not a PLIC source on this machine
+    InvalidSource,
+    /// None of the harts can take interrupts
+    NoHart,
+}
+
+/// How a source is routed, see `routes`
+#[derive(Debug, Clone, Copy)]
+pub struct Route {
+    pub id: InterruptId,
+    pub priority: u32,
+    /// Harts the source should go to
+    pub affinity: HartMask,
+    /// Harts that have the source enabled
+    pub enabled: HartMask,
+}
+
+impl From<u32> for Priority {
+    fn from(priority: u32) -> Self {
+        match priority {
+            0 => Self::Zero,
+            1 => Self::One,
+            2 => Self::Two,
+            3 => Self::Three,
+            4 => Self::Four,
+            5 => Self::Five,
+            6 => Self::Six,
+            _ => Self::Seven,
+        }
+    }
+}
+
 impl From<Threshold> for Priority {
     fn from(threshold: Threshold) -> Self {
         match threshold {
@@ -54,21 +100,83 @@ impl From<Threshold> for Priority {
     }
 }
 
+/// Interrupt sources on QEMU virt
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum InterruptId {
     Unknown = 0,
+    /// virtio-mmio slots 0..7, at 0x1000_1000 + slot * 0x1000
+    Virtio0 = 1,
+    Virtio1 = 2,
+    Virtio2 = 3,
+    Virtio3 = 4,
+    Virtio4 = 5,
+    Virtio5 = 6,
+    Virtio6 = 7,
+    Virtio7 = 8,
     Uart0 = 10,
+    Rtc = 11,
+    /// PCIe INTA..INTD
+    Pcie0 = 32,
+    Pcie1 = 33,
+    Pcie2 = 34,
+    Pcie3 = 35,
 }
 
 impl From<u32> for InterruptId {
     fn from(irq: u32) -> Self {
         match irq {
+            1 => Self::Virtio0,
+            2 => Self::Virtio1,
+            3 => Self::Virtio2,
+            4 => Self::Virtio3,
+            5 => Self::Virtio4,
+            6 => Self::Virtio5,
+            7 => Self::Virtio6,
+            8 => Self::Virtio7,
             10 => Self::Uart0,
+            11 => Self::Rtc,
+            32 => Self::Pcie0,
+            33 => Self::Pcie1,
+            34 => Self::Pcie2,
+            35 => Self::Pcie3,
             _ => Self::Unknown,
         }
     }
 }
 
+impl InterruptId {
+    /// Every known source
+    pub const ALL: [InterruptId; 14] = [
+        Self::Virtio0,
+        Self::Virtio1,
+        Self::Virtio2,
+        Self::Virtio3,
+        Self::Virtio4,
+        Self::Virtio5,
+        Self::Virtio6,
+        Self::Virtio7,
+        Self::Uart0,
+        Self::Rtc,
+        Self::Pcie0,
+        Self::Pcie1,
+        Self::Pcie2,
+        Self::Pcie3,
+    ];
+
+    /// Interrupt source of virtio-mmio slot `slot`
+    pub fn virtio(slot: usize) -> Self {
+        match slot {
+            0..=7 => Self::from(slot as u32 + 1),
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Word and bit of the source in the enable and pending bitmaps
+    const fn word_bit(self) -> (usize, u32) {
+        (self as usize / 32, 1 << (self as u32 % 32))
+    }
+}
+
 pub struct Plic;
 
 impl Plic {
@@ -78,64 +186,93 @@ impl Plic {
     }
 
     /// Initialize the PLIC.
-    /// Enables an interrupt id.
+    /// Gives an interrupt id the lowest priority that is not masked.
     pub unsafe fn init(&mut self, id: InterruptId) {
-        let enabled = PLIC_BASE as *mut u32;
-        enabled.add(id as usize).write_volatile(1);
+        self.set_priority(id, Priority::One);
     }
 
-    /// Enable an interrupt id.
+    /// Enable an interrupt id on the current hart.
     pub fn enable(&mut self, id: InterruptId) {
-        let enable = Self::senable(thread_pointer()) as *mut u32;
+        self.enable_on(thread_pointer(), id);
+    }
+
+    /// Disable an interrupt id on the current hart.
+    pub fn disable(&mut self, id: InterruptId) {
+        self.disable_on(thread_pointer(), id);
+    }
+
+    /// Enable an interrupt id in the S-mode context of `hart`
+    pub fn enable_on(&mut self, hart: usize, id: InterruptId) {
         // The plic enable register contains a bitmap over enabled interrupts.
-        let id = 1 << id as u32;
+        let (word, bit) = id.word_bit();
+        let enable = Self::senable(hart) as *mut u32;
         unsafe {
-            enable.write_volatile(enable.read_volatile() | id);
+            let enable = enable.add(word);
+            enable.write_volatile(enable.read_volatile() | bit);
         }
     }
 
-    /// Disable an interrupt id.
-    pub fn disable(&mut self, id: InterruptId) {
-        let disable = Self::senable(thread_pointer()) as *mut u32;
-        // The plic enable register contains a bitmap over enabled interrupts.
-        let id = !(1 << id as u32);
+    /// Disable an interrupt id in the S-mode context of `hart`
+    pub fn disable_on(&mut self, hart: usize, id: InterruptId) {
+        let (word, bit) = id.word_bit();
+        let disable = Self::senable(hart) as *mut u32;
         unsafe {
-            disable.write_volatile(disable.read_volatile() & id);
+            let disable = disable.add(word);
+            disable.write_volatile(disable.read_volatile() & !bit);
         }
     }
 
+    /// Check if an interrupt id is enabled in the S-mode context of `hart`
+    pub fn is_enabled_on(&self, hart: usize, id: InterruptId) -> bool {
+        let (word, bit) = id.word_bit();
+        let enable = Self::senable(hart) as *const u32;
+        unsafe { enable.add(word).read_volatile() & bit != 0 }
+    }
+
     /// Set priority for an interrupt
     ///
-    /// Priority must be in range [0..7]
+    /// The priority is global, shared by all harts.
     pub fn set_priority(&mut self, id: InterruptId, priority: Priority) {
-        let priority = priority as u32;
-        let reg = Plic::spriority(thread_pointer()) as *mut u32;
+        let reg = Self::priority_reg(id) as *mut u32;
         unsafe {
-            // Interrupt id offset is: PLIC_PRIORITY + 4 * id
-            // Reg is u32, no neeed to multiply by 4
-            reg.add(id as usize).write_volatile(priority);
+            reg.write_volatile(priority as u32);
         }
     }
 
-    /// Set global interrupt threshold.
+    /// Priority of an interrupt
+    pub fn priority(&self, id: InterruptId) -> u32 {
+        let reg = Self::priority_reg(id) as *const u32;
+        unsafe { reg.read_volatile() }
+    }
+
+    /// Set interrupt threshold of the current hart.
     ///
     /// Interrupt with priority equal to or below the threshlod will be masked.
     /// 7 will mask all interrupts and 0 will allow all interrupts.
-    ///
-    /// Threshold must be in [0..7]
     pub fn set_threshold(&mut self, threshold: Threshold) {
+        self.set_threshold_on(thread_pointer(), threshold);
+    }
+
+    /// Set interrupt threshold of the S-mode context of `hart`
+    pub fn set_threshold_on(&mut self, hart: usize, threshold: Threshold) {
         let threshold = Priority::from(threshold) as u32;
-        let reg = Plic::spriority(thread_pointer()) as *mut u32;
+        let reg = Self::sthreshold(hart) as *mut u32;
         unsafe {
             reg.write_volatile(threshold);
         }
     }
 
+    /// Interrupt threshold of the S-mode context of `hart`
+    pub fn threshold(&self, hart: usize) -> u32 {
+        let reg = Self::sthreshold(hart) as *const u32;
+        unsafe { reg.read_volatile() }
+    }
+
     /// Check if a given interrupt is pending
     pub fn is_pending(&mut self, id: InterruptId) -> bool {
+        let (word, bit) = id.word_bit();
         let pending = PLIC_PENDING as *const u32;
-        let id = 1 << id as u32;
-        unsafe { pending.read_volatile() & id != 0 }
+        unsafe { pending.add(word).read_volatile() & bit != 0 }
     }
 
     /// Complete an interrupt by id
@@ -152,16 +289,39 @@ impl Plic {
     ///
     /// The PLIC will sort by priority and return the ID of the pending interrupt
     pub fn next(&mut self) -> Option<InterruptId> {
-        let reg = Self::sclaim(thread_pointer()) as *const u32;
-
-        let id = unsafe { reg.read_volatile() };
-
-        match id {
+        match self.claim() {
             0 => None,
             id => Some(InterruptId::from(id)),
         }
     }
 
+    /// Claim the next available interrupt, 0 if there is none
+    ///
+    /// Unlike `next` this keeps the number of sources without an
+    /// `InterruptId`, so that they can be completed.
+    pub fn claim(&mut self) -> u32 {
+        let reg = Self::sclaim(thread_pointer()) as *const u32;
+        unsafe { reg.read_volatile() }
+    }
+
+    /// Move the interrupts enabled on hart `from` over to hart `to`
+    ///
+    /// Leaves the S-mode context of `from` with every interrupt disabled and
+    /// masked.
+    pub fn migrate(&mut self, from: usize, to: usize) {
+        let src = Self::senable(from) as *mut u32;
+        let dst = Self::senable(to) as *mut u32;
+        unsafe {
+            for word in 0..PLIC_SOURCES / 32 {
+                let enabled = src.add(word).read_volatile();
+                dst.add(word)
+                    .write_volatile(dst.add(word).read_volatile() | enabled);
+                src.add(word).write_volatile(0);
+            }
+        }
+        self.set_threshold_on(from, Threshold::None);
+    }
+
     const fn _menable(hart: usize) -> usize {
         _PLIC_MENABLE_BASE + hart * 0x100
     }
@@ -170,12 +330,16 @@ impl Plic {
         PLIC_SENABLE_BASE + hart * 0x100
     }
 
-    const fn _mpriority(hart: usize) -> usize {
-        _PLIC_MPRIORITY_BASE + hart * 0x2000
+    const fn priority_reg(id: InterruptId) -> usize {
+        PLIC_PRIORITY + id as usize * 4
+    }
+
+    const fn _mthreshold(hart: usize) -> usize {
+        _PLIC_MTHRESHOLD_BASE + hart * 0x2000
     }
 
-    const fn spriority(hart: usize) -> usize {
-        PLIC_SPRIORITY_BASE + hart * 0x2000
+    const fn sthreshold(hart: usize) -> usize {
+        PLIC_STHRESHOLD_BASE + hart * 0x2000
     }
 
     const fn _mclaim(hart: usize) -> usize {
@@ -189,6 +353,13 @@ impl Plic {
 
 static mut _PLIC: Plic = Plic::new();
 
+const BOOT_HART: AtomicUsize = AtomicUsize::new(1);
+
+/// Harts each source should be routed to, as bits of a `HartMask`
+static AFFINITY: [AtomicUsize; PLIC_SOURCES] = [BOOT_HART; PLIC_SOURCES];
+/// Serializes changes of the enable bits
+static ROUTING: Mutex<()> = Mutex::new(());
+
 pub fn plic() -> &'static mut Plic {
     unsafe { addr_of_mut!(_PLIC).as_mut().unwrap() }
 }
@@ -197,14 +368,116 @@ pub fn plic() -> &'static mut Plic {
 /// Should only be called once
 pub unsafe fn init() {
     info!("Initating PLIC");
-    let plic = plic();
-    plic.init(InterruptId::Uart0);
 }
 
 /// Initiate the plic for the current HART
+///
+/// Sources are enabled by `interrupt::request_irq`.
 pub fn hartinit() {
+    plic().set_threshold(Threshold::All);
+}
+
+/// Harts an interrupt source is routed to
+pub fn affinity(id: InterruptId) -> HartMask {
+    HartMask::from_bits(AFFINITY[id as usize].load(Ordering::Relaxed))
+}
+
+/// Route an interrupt source to the harts in `mask`
+///
+/// Takes effect at once if the source is enabled. Harts that are not
+/// started are skipped, at least one hart in `mask` must be started.
+pub fn set_affinity(id: InterruptId, mask: HartMask) -> Result<(), Error> {
+    if id == InterruptId::Unknown {
+        return Err(Error::InvalidSource);
+    }
+    if mask.bits() & hart::online_mask().bits() == 0 {
+        return Err(Error::NoHart);
+    }
+
+    let _guard = ROUTING.lock();
     let plic = plic();
-    plic.enable(InterruptId::Uart0);
-    plic.set_threshold(Threshold::All);
-    plic.set_priority(InterruptId::Uart0, Priority::One);
+    AFFINITY[id as usize].store(mask.bits(), Ordering::Relaxed);
+    let enabled = (0..MAX_HARTS).any(|hart| plic.is_enabled_on(hart, id));
+    if enabled {
+        apply(plic, id, mask);
+    }
+    Ok(())
+}
+
+/// Enable an interrupt source on the harts of its affinity
+///
+/// Before the harts are started it is enabled on the boot hart.
+pub fn route(id: InterruptId) {
+    let _guard = ROUTING.lock();
+    apply(plic(), id, affinity(id));
+}
+
+/// Disable an interrupt source on every hart
+pub fn unroute(id: InterruptId) {
+    let _guard = ROUTING.lock();
+    let plic = plic();
+    for hart in 0..MAX_HARTS {
+        plic.disable_on(hart, id);
+    }
+}
+
+fn apply(plic: &mut Plic, id: InterruptId, mask: HartMask) {
+    let mut online = HartMask::from_bits(mask.bits() & hart::online_mask().bits());
+    if online.is_empty() {
+        online = HartMask::single(thread_pointer());
+    }
+    for hart in 0..MAX_HARTS {
+        if online.contains(hart) {
+            plic.enable_on(hart, id);
+        } else {
+            plic.disable_on(hart, id);
+        }
+    }
+}
+
+/// Move the interrupts of hart `from` over to hart `to`
+///
+/// Used when `from` is stopped, the affinity of its sources follows.
+pub fn migrate(from: usize, to: usize) {
+    let _guard = ROUTING.lock();
+    for id in InterruptId::ALL {
+        let mut mask = affinity(id);
+        if mask.contains(from) {
+            mask.clear(from);
+            mask.set(to);
+            AFFINITY[id as usize].store(mask.bits(), Ordering::Relaxed);
+        }
+    }
+    plic().migrate(from, to);
+}
+
+/// The routing table of every known source
+pub fn routes() -> impl Iterator<Item = Route> {
+    InterruptId::ALL.into_iter().map(|id| {
+        let plic = plic();
+        let mut enabled = HartMask::empty();
+        for hart in (0..MAX_HARTS).filter(|&hart| plic.is_enabled_on(hart, id)) {
+            enabled.set(hart);
+        }
+        Route {
+            id,
+            priority: plic.priority(id),
+            affinity: affinity(id),
+            enabled,
+        }
+    })
+}
+
+/// Log the routing table
+pub fn dump_routes() {
+    info!("PLIC routes:");
+    for route in routes() {
+        info!(
+            "\t{:?}: priority {}, affinity {:#b}, enabled {:#b}",
+            route.id,
+            route.priority,
+            route.affinity.bits(),
+            route.enabled.bits()
+        );
+    }
 }
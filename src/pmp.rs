@@ -0,0 +1,437 @@
+// Physical Memory Protection (PMP)
+//
+// Every hart has 16 PMP entries, programmed from machine mode. An entry is
+// a pmpaddr register and a byte in one of the pmpcfg registers. On RV64 only
+// the even numbered pmpcfg registers exist, each holding 8 entries.
+//
+// The lowest numbered entry matching an address decides the access. S-mode
+// and U-mode accesses that match no entry fail. M-mode accesses only honour
+// entries with the L bit set.
+
+use crate::cmdline;
+use crate::ex_table;
+use crate::extable;
+use crate::kaslr;
+use crate::mem;
+use crate::page::Attribute;
+use crate::symbols::{MACHINE_END, MACHINE_START};
+use crate::trap::{self, Trap, TrapFrame};
+
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{info, warn};
+use riscv::register::satp;
+
+pub const PMP_ENTRIES: usize = 16;
+
+/// pmpaddr value for a NAPOT entry covering the whole physical address space
+const ALL_MEMORY: usize = usize::MAX >> 10;
+
+const CFG_READ: u8 = 1 << 0;
+const CFG_WRITE: u8 = 1 << 1;
+const CFG_EXECUTE: u8 = 1 << 2;
+const CFG_MODE_SHIFT: u8 = 3;
+const CFG_MODE_MASK: u8 = 0b11 << CFG_MODE_SHIFT;
+const CFG_LOCKED: u8 = 1 << 7;
+
+/// Address matching mode
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Mode {
+    /// Entry is disabled
+    Off = 0,
+    /// Top of range, the bottom is the address of the previous entry
+    Tor = 1,
+    /// Naturally aligned four byte region
+    Na4 = 2,
+    /// Naturally aligned power of two region, at least eight bytes
+    Napot = 3,
+}
+
+impl From<u8> for Mode {
+    fn from(cfg: u8) -> Self {
+        match (cfg & CFG_MODE_MASK) >> CFG_MODE_SHIFT {
+            1 => Self::Tor,
+            2 => Self::Na4,
+            3 => Self::Napot,
+            _ => Self::Off,
+        }
+    }
+}
+
+/// Access permissions of an entry
+///
+/// Write without read is reserved and can not be expressed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Permission {
+    None = 0,
+    Read = 0b001,
+    Execute = 0b100,
+    ReadWrite = 0b011,
+    ReadExecute = 0b101,
+    ReadWriteExecute = 0b111,
+}
+
+/// A PMP entry as read back from the hardware
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    index: usize,
+    cfg: u8,
+    addr: usize,
+}
+
+impl Entry {
+    pub const fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn mode(&self) -> Mode {
+        Mode::from(self.cfg)
+    }
+
+    pub const fn readable(&self) -> bool {
+        self.cfg & CFG_READ != 0
+    }
+
+    pub const fn writable(&self) -> bool {
+        self.cfg & CFG_WRITE != 0
+    }
+
+    pub const fn executable(&self) -> bool {
+        self.cfg & CFG_EXECUTE != 0
+    }
+
+    pub const fn locked(&self) -> bool {
+        self.cfg & CFG_LOCKED != 0
+    }
+
+    /// Physical address range `[start, end)` covered by the entry
+    ///
+    /// Returns None for entries that are turned off.
+    pub fn range(&self) -> Option<(usize, usize)> {
+        match self.mode() {
+            Mode::Off => None,
+            Mode::Tor => {
+                let start = match self.index {
+                    0 => 0,
+                    index => unsafe { read_addr(index - 1) << 2 },
+                };
+                Some((start, self.addr << 2))
+            }
+            Mode::Na4 => Some((self.addr << 2, (self.addr << 2) + 4)),
+            Mode::Napot => {
+                let ones = self.addr.trailing_ones() as usize;
+                let start = (self.addr & !((1 << ones) - 1)) << 2;
+                Some((start, start.wrapping_add(8 << ones)))
+            }
+        }
+    }
+}
+
+/// Read back entry `index` of the current hart
+pub fn entry(index: usize) -> Entry {
+    assert!(index < PMP_ENTRIES, "invalid pmp entry {}", index);
+    unsafe {
+        Entry {
+            index,
+            cfg: read_cfg(index),
+            addr: read_addr(index),
+        }
+    }
+}
+
+/// Program entry `index` with an already encoded pmpaddr value
+///
+/// # Safety
+///
+/// Must run in machine mode. A locked entry can not be changed until reset
+/// and also restricts machine mode.
+pub unsafe fn set(index: usize, mode: Mode, addr: usize, perm: Permission, locked: bool) {
+    assert!(index < PMP_ENTRIES, "invalid pmp entry {}", index);
+    assert!(!entry(index).locked(), "pmp entry {} is locked", index);
+
+    let mut cfg = (mode as u8) << CFG_MODE_SHIFT | perm as u8;
+    if locked {
+        cfg |= CFG_LOCKED;
+    }
+
+    // Turn the entry off while the address changes
+    write_cfg(index, 0);
+    write_addr(index, addr);
+    write_cfg(index, cfg);
+}
+
+/// Protect `[start, end)` with a TOR entry
+///
+/// The bottom of the range is kept in entry `index - 1`, which is turned off.
+/// For entry 0 the bottom of the range is always address 0.
+///
+/// # Safety
+///
+/// See [`set`].
+pub unsafe fn set_tor(index: usize, start: usize, end: usize, perm: Permission, locked: bool) {
+    assert!(start % 4 == 0 && end % 4 == 0 && start <= end);
+    if index == 0 {
+        assert!(start == 0, "pmp entry 0 can only cover [0, end)");
+    } else {
+        set(index - 1, Mode::Off, start >> 2, Permission::None, false);
+    }
+    set(index, Mode::Tor, end >> 2, perm, locked);
+}
+
+/// Protect the four bytes at `addr` with a NA4 entry
+///
+/// # Safety
+///
+/// See [`set`].
+pub unsafe fn set_na4(index: usize, addr: usize, perm: Permission, locked: bool) {
+    assert!(addr % 4 == 0);
+    set(index, Mode::Na4, addr >> 2, perm, locked);
+}
+
+/// Protect `size` bytes at `base` with a NAPOT entry
+///
+/// `size` must be a power of two, at least eight, and `base` aligned to it.
+///
+/// # Safety
+///
+/// See [`set`].
+pub unsafe fn set_napot(index: usize, base: usize, size: usize, perm: Permission, locked: bool) {
+    assert!(size.is_power_of_two() && size >= 8);
+    assert!(base % size == 0, "0x{:x} not aligned to 0x{:x}", base, size);
+    set(
+        index,
+        Mode::Napot,
+        (base | (size / 2 - 1)) >> 2,
+        perm,
+        locked,
+    );
+}
+
+/// Program the PMP of the current hart
+///
+/// Entry 0-1: the machine mode code and data, no access from S-mode.
+/// Entry 2: everything else, full access from S-mode.
+///
+/// The machine mode region does not set L, that would apply the empty
+/// permissions to machine mode as well.
+///
+/// # Safety
+///
+/// Must be called in machine mode, once per hart.
+pub unsafe fn hartinit() {
+    for index in 0..PMP_ENTRIES {
+        if !entry(index).locked() {
+            write_cfg(index, 0);
+        }
+    }
+
+    set_tor(1, MACHINE_START(), MACHINE_END(), Permission::None, false);
+    set(
+        2,
+        Mode::Napot,
+        ALL_MEMORY,
+        Permission::ReadWriteExecute,
+        false,
+    );
+}
+
+/// List the configured entries of the current hart
+pub fn dump() {
+    info!("PMP entries:");
+    for index in 0..PMP_ENTRIES {
+        let entry = entry(index);
+        if let Some((start, end)) = entry.range() {
+            info!(
+                "\t{:2}: {:5?} 0x{:016X}->0x{:016X} {}{}{}{}",
+                index,
+                entry.mode(),
+                start,
+                end,
+                if entry.readable() { "R" } else { "-" },
+                if entry.writable() { "W" } else { "-" },
+                if entry.executable() { "X" } else { "-" },
+                if entry.locked() { "L" } else { "-" },
+            );
+        }
+    }
+}
+
+/// Where `test` maps the machine mode memory, below the alias of `patch`
+const TEST_ADDR: usize = 0xffff_ffff_ffe0_0000;
+
+extern "C" {
+    /// Load from `addr` into `value`, returns false if the load faulted
+    fn __pmp_probe_load(addr: usize, value: *mut usize) -> bool;
+    /// Store `value` at `addr`, returns false if the store faulted
+    fn __pmp_probe_store(addr: usize, value: usize) -> bool;
+}
+
+global_asm!(
+    r#"
+.global __pmp_probe_load
+.align 4
+__pmp_probe_load:
+1:
+    ld t0, 0(a0)
+    sd t0, 0(a1)
+    li a0, 1
+    ret
+2:
+    li a0, 0
+    ret
+
+.global __pmp_probe_store
+.align 4
+__pmp_probe_store:
+3:
+    sd a1, 0(a0)
+    li a0, 1
+    ret
+4:
+    li a0, 0
+    ret
+"#,
+    ex_table!("1b", "2b"),
+    ex_table!("3b", "4b"),
+);
+
+/// Traps of the probes, claimed while `test` runs
+const FAULTS: [Trap; 2] = [Trap::LoadAccessFault, Trap::StoreAccessFault];
+
+/// scause of the last access fault taken by a probe
+static PROBE_CAUSE: AtomicUsize = AtomicUsize::new(0);
+
+fn probe_fault(frame: &mut TrapFrame) -> bool {
+    if !extable::fixup_exception(frame) {
+        return false;
+    }
+    PROBE_CAUSE.store(frame.scause, Ordering::Relaxed);
+    true
+}
+
+/// Run `probe` and check that it fails with the access fault `expected`
+fn check(what: &str, expected: Trap, probe: impl FnOnce() -> bool) -> bool {
+    PROBE_CAUSE.store(0, Ordering::Relaxed);
+    if probe() {
+        warn!("pmp_test: {} of machine mode memory went through", what);
+        return false;
+    }
+    let cause = PROBE_CAUSE.load(Ordering::Relaxed);
+    if cause == 0 || Trap::from(cause) != expected {
+        warn!("pmp_test: {} faulted, but not in the PMP", what);
+        return false;
+    }
+    info!("pmp_test: {} of machine mode memory: {}", what, expected);
+    true
+}
+
+/// Check that S-mode can not touch machine mode memory, with `pmp_test`
+///
+/// The start of the machine mode region is read and written from S-mode,
+/// both have to fail with an access fault. With paging on, the page is
+/// mapped read and write first, so that the page table lets the accesses
+/// through to the PMP. The write stores what the read got, so it changes
+/// nothing should the PMP let both through, and is left out if the read
+/// faulted for another reason.
+pub fn test() {
+    if !cmdline::has("pmp_test") {
+        return;
+    }
+
+    // Machine mode runs at the load address, also with KASLR
+    let phys = kaslr::link_address(MACHINE_START());
+    let paging = satp::read().bits() != 0;
+    let addr = if paging {
+        unsafe { mem::map_page(TEST_ADDR, phys, Attribute::ReadWrite) };
+        TEST_ADDR
+    } else {
+        phys
+    };
+
+    let claimed = FAULTS.map(|trap| trap::register_handler(trap, probe_fault).is_ok());
+    if claimed.contains(&false) {
+        warn!("pmp_test: access faults are claimed already");
+    } else {
+        let mut value = 0;
+        let mut loaded = false;
+        let load = check("load", Trap::LoadAccessFault, || {
+            loaded = unsafe { __pmp_probe_load(addr, &mut value) };
+            loaded
+        });
+        // Without the value, only write where the PMP stopped the read
+        let store = (load || loaded)
+            && check("store", Trap::StoreAccessFault, || unsafe {
+                __pmp_probe_store(addr, value)
+            });
+        info!(
+            "pmp_test: {}",
+            if load && store { "passed" } else { "FAILED" }
+        );
+    }
+    for (trap, claimed) in FAULTS.into_iter().zip(claimed) {
+        if claimed {
+            trap::unregister_handler(trap);
+        }
+    }
+
+    if paging {
+        unsafe { mem::unmap_page(TEST_ADDR) };
+    }
+}
+
+macro_rules! pmpaddr {
+    (read, $index:expr) => {
+        pmpaddr!(@read $index, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15)
+    };
+    (write, $index:expr, $value:expr) => {
+        pmpaddr!(@write $index, $value, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15)
+    };
+    (@read $index:expr, $($i:literal),+) => {{
+        let bits: usize;
+        match $index {
+            $($i => asm!(concat!("csrr {}, pmpaddr", $i), out(reg) bits),)+
+            _ => unreachable!(),
+        }
+        bits
+    }};
+    (@write $index:expr, $value:expr, $($i:literal),+) => {
+        match $index {
+            $($i => asm!(concat!("csrw pmpaddr", $i, ", {}"), in(reg) $value),)+
+            _ => unreachable!(),
+        }
+    };
+}
+
+unsafe fn read_addr(index: usize) -> usize {
+    pmpaddr!(read, index)
+}
+
+unsafe fn write_addr(index: usize, value: usize) {
+    pmpaddr!(write, index, value)
+}
+
+/// Read the pmpcfg register holding entry `index`
+unsafe fn read_cfg_reg(index: usize) -> usize {
+    let bits: usize;
+    match index / 8 {
+        0 => asm!("csrr {}, pmpcfg0", out(reg) bits),
+        1 => asm!("csrr {}, pmpcfg2", out(reg) bits),
+        _ => unreachable!(),
+    }
+    bits
+}
+
+unsafe fn read_cfg(index: usize) -> u8 {
+    (read_cfg_reg(index) >> (index % 8 * 8)) as u8
+}
+
+unsafe fn write_cfg(index: usize, cfg: u8) {
+    let shift = index % 8 * 8;
+    let bits = read_cfg_reg(index) & !(0xff << shift) | (cfg as usize) << shift;
+    match index / 8 {
+        0 => asm!("csrw pmpcfg0, {}", in(reg) bits),
+        1 => asm!("csrw pmpcfg2, {}", in(reg) bits),
+        _ => unreachable!(),
+    }
+}
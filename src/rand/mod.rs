@@ -1 +1,11 @@
 pub mod xorshift;
+
+/// Mix `value` into `state`
+///
+/// The splitmix64 finalizer, used to fold entropy sources into a seed.
+pub fn mix(state: u64, value: u64) -> u64 {
+    let mut z = (state.rotate_left(23) ^ value).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,141 @@
+// Goldfish real time clock
+//
+// The clock counts nanoseconds since the Unix epoch. Reading TIME_LOW latches
+// the upper half into TIME_HIGH, so the low word must be read first. Writing
+// ALARM_LOW arms the alarm, which raises IRQ 11 on QEMU virt.
+
+use crate::arch::riscv::without_interrupts;
+use crate::interrupt::{self, IrqReturn};
+use crate::plic::InterruptId;
+use crate::time::{self, DateTime};
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use log::{info, warn};
+use spin::Mutex;
+
+pub const RTC_BASE: usize = 0x0010_1000;
+pub const RTC_SIZE: usize = 0x1000;
+
+mod offset {
+    pub const TIME_LOW: usize = 0x00;
+    pub const TIME_HIGH: usize = 0x04;
+    pub const ALARM_LOW: usize = 0x08;
+    pub const ALARM_HIGH: usize = 0x0c;
+    pub const IRQ_ENABLED: usize = 0x10;
+    pub const CLEAR_ALARM: usize = 0x14;
+    pub const ALARM_STATUS: usize = 0x18;
+    pub const CLEAR_INTERRUPT: usize = 0x1c;
+}
+
+/// Serializes the two step reads and writes of the 64 bit registers
+///
+/// Only locked with interrupts off, alarm handlers read the clock and set
+/// the next alarm from the RTC interrupt.
+static RTC: Mutex<Rtc> = Mutex::new(Rtc::new(RTC_BASE));
+
+/// Called from the alarm interrupt, 0 if none
+static ALARM_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Rtc {
+    base_address: usize,
+}
+
+impl Rtc {
+    pub const fn new(base_address: usize) -> Self {
+        Self { base_address }
+    }
+
+    /// Nanoseconds since the Unix epoch
+    pub fn read(&mut self) -> u64 {
+        let low = self.read_reg(offset::TIME_LOW) as u64;
+        let high = self.read_reg(offset::TIME_HIGH) as u64;
+        high << 32 | low
+    }
+
+    /// Raise an interrupt once the clock reaches `ns`
+    pub fn set_alarm(&mut self, ns: u64) {
+        self.write_reg(offset::IRQ_ENABLED, 1);
+        self.write_reg(offset::ALARM_HIGH, (ns >> 32) as u32);
+        self.write_reg(offset::ALARM_LOW, ns as u32);
+    }
+
+    /// Disarm a pending alarm
+    pub fn clear_alarm(&mut self) {
+        self.write_reg(offset::CLEAR_ALARM, 1);
+    }
+
+    /// Check if an alarm is armed
+    pub fn alarm_armed(&mut self) -> bool {
+        self.read_reg(offset::ALARM_STATUS) != 0
+    }
+
+    /// Acknowledge the alarm interrupt
+    pub fn clear_interrupt(&mut self) {
+        self.write_reg(offset::CLEAR_INTERRUPT, 1);
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        let ptr = (self.base_address + offset) as *const u32;
+        unsafe { ptr.read_volatile() }
+    }
+
+    fn write_reg(&self, offset: usize, value: u32) {
+        let ptr = (self.base_address + offset) as *mut u32;
+        unsafe { ptr.write_volatile(value) }
+    }
+}
+
+/// Time since the Unix epoch, read from the RTC
+pub fn now() -> Duration {
+    Duration::from_nanos(without_interrupts(|| RTC.lock().read()))
+}
+
+/// Call `handler` from the RTC interrupt once the clock reaches `at`
+///
+/// Replaces any alarm already set.
+pub fn set_alarm(at: Duration, handler: fn()) {
+    without_interrupts(|| {
+        let mut rtc = RTC.lock();
+        ALARM_HANDLER.store(handler as usize, Ordering::Release);
+        rtc.set_alarm(at.as_nanos() as u64);
+    });
+}
+
+/// Cancel the alarm
+pub fn clear_alarm() {
+    without_interrupts(|| {
+        RTC.lock().clear_alarm();
+        ALARM_HANDLER.store(0, Ordering::Release);
+    });
+}
+
+/// Handle the RTC alarm interrupt
+pub fn rtc_interrupt(_: InterruptId, _: usize) -> IrqReturn {
+    // A single register write, no need to wait for the lock
+    Rtc::new(RTC_BASE).clear_interrupt();
+
+    match ALARM_HANDLER.swap(0, Ordering::AcqRel) {
+        0 => warn!("RTC alarm without a handler"),
+        handler => {
+            let handler: fn() = unsafe { core::mem::transmute(handler) };
+            handler();
+        }
+    }
+    IrqReturn::Handled
+}
+
+/// Initiate the RTC
+///
+/// Enables the alarm interrupt on the current hart.
+pub unsafe fn init() {
+    info!("Initating RTC");
+    if let Err(err) = interrupt::request_irq(InterruptId::Rtc, rtc_interrupt, 0) {
+        warn!("RTC: no interrupt, {:?}", err);
+    }
+
+    without_interrupts(|| RTC.lock().clear_alarm());
+    time::sync_realtime(now());
+    info!("RTC: {}", DateTime::from(now()));
+}
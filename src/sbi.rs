@@ -0,0 +1,104 @@
+// Supervisor Binary Interface (SBI) calls
+//
+// The kernel runs its own machine mode, which serves the few calls S-mode
+// makes, see `timervec`. Only the base extension's probe and the debug
+// console's write byte are implemented, everything else fails with
+// ERR_NOT_SUPPORTED like it would on a firmware without the extension.
+
+use crate::console::Backend;
+
+use core::arch::global_asm;
+
+pub const EXT_BASE: usize = 0x10;
+/// Debug console, "DBCN"
+pub const EXT_DBCN: usize = 0x4442_434e;
+
+const BASE_PROBE_EXTENSION: usize = 3;
+const DBCN_WRITE_BYTE: usize = 2;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Error {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    Other(isize),
+}
+
+impl From<isize> for Error {
+    fn from(error: isize) -> Self {
+        match error {
+            -1 => Error::Failed,
+            -2 => Error::NotSupported,
+            -3 => Error::InvalidParam,
+            -4 => Error::Denied,
+            -5 => Error::InvalidAddress,
+            error => Error::Other(error),
+        }
+    }
+}
+
+/// Returned in a0 and a1
+#[repr(C)]
+struct SbiRet {
+    error: isize,
+    value: usize,
+}
+
+extern "C" {
+    fn sbi_call(ext: usize, fid: usize, arg0: usize) -> SbiRet;
+}
+
+global_asm!(
+    r#"
+.global sbi_call
+.align 4
+sbi_call:
+    mv a7, a0
+    mv a6, a1
+    mv a0, a2
+    ecall
+    ret
+"#
+);
+
+fn call(ext: usize, fid: usize, arg0: usize) -> Result<usize, Error> {
+    let ret = unsafe { sbi_call(ext, fid, arg0) };
+    match ret.error {
+        0 => Ok(ret.value),
+        error => Err(Error::from(error)),
+    }
+}
+
+/// Check if the extension `ext` is available
+pub fn probe_extension(ext: usize) -> bool {
+    call(EXT_BASE, BASE_PROBE_EXTENSION, ext).map_or(false, |value| value != 0)
+}
+
+/// Write a byte to the debug console, waits until it can
+pub fn debug_console_write_byte(byte: u8) -> Result<(), Error> {
+    call(EXT_DBCN, DBCN_WRITE_BYTE, byte as usize).map(|_| ())
+}
+
+/// The debug console, as a console backend
+///
+/// Machine mode writes the UART itself, with none of the locks or buffers
+/// of the UART driver in the way.
+pub struct DebugConsole;
+
+pub static CONSOLE: DebugConsole = DebugConsole;
+
+impl Backend for DebugConsole {
+    fn name(&self) -> &'static str {
+        "sbi"
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        for &byte in bytes {
+            if debug_console_write_byte(byte).is_err() {
+                break;
+            }
+        }
+    }
+}
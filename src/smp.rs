@@ -0,0 +1,94 @@
+// Cross-hart function calls
+//
+// One call is in flight at a time. The caller publishes the function and
+// the harts it is for, then waits until each of them has picked it up, and
+// with `wait` until each has run it. A call is not published before every
+// hart has finished the one before, which the caller may not have waited
+// for.
+
+use crate::arch::riscv::thread_pointer;
+use crate::hart::{self, HartMask};
+use crate::ipi::{self, Ipi};
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+/// Serializes callers
+static CALL: Mutex<()> = Mutex::new(());
+/// The function to run
+static FUNC: AtomicUsize = AtomicUsize::new(0);
+/// Harts that have not read `FUNC` yet
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+/// Harts that have not finished running `FUNC` yet
+static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+/// Run `func` on every started hart in `mask`
+///
+/// Runs in interrupt context on the other harts, and directly on the
+/// current hart if it is in `mask`. With `wait` the call returns when
+/// every hart has finished, else as soon as every hart has started.
+///
+/// Must be called with interrupts enabled, two harts calling each other
+/// would otherwise wait forever. No hart stops while the call is in
+/// flight.
+pub fn smp_call_function(mask: HartMask, func: fn(), wait: bool) {
+    let _hotplug = hart::hotplug_lock();
+    let hart = thread_pointer();
+    let mut targets = HartMask::from_bits(mask.bits() & hart::online_mask().bits());
+    let local = targets.contains(hart);
+    targets.clear(hart);
+
+    if !targets.is_empty() {
+        let _guard = CALL.lock();
+        // FINISHED is shared, a hart still running the last call would
+        // clear its bit for this one
+        while FINISHED.load(Ordering::Acquire) != 0 {
+            spin_loop();
+        }
+        FUNC.store(func as usize, Ordering::Relaxed);
+        FINISHED.store(targets.bits(), Ordering::Relaxed);
+        STARTED.store(targets.bits(), Ordering::Release);
+
+        ipi::send_mask(targets, Ipi::CallFunction);
+
+        // The next caller may not replace FUNC before everyone read it
+        while STARTED.load(Ordering::Acquire) != 0 {
+            spin_loop();
+        }
+
+        if local {
+            func();
+        }
+
+        if wait {
+            while FINISHED.load(Ordering::Acquire) != 0 {
+                spin_loop();
+            }
+        }
+    } else if local {
+        func();
+    }
+}
+
+/// Run `func` on every started hart, including the current one
+pub fn on_each_hart(func: fn(), wait: bool) {
+    smp_call_function(HartMask::all(), func, wait);
+}
+
+/// Handle a `CallFunction` IPI
+pub(crate) fn call_function_interrupt() {
+    let bit = HartMask::single(thread_pointer()).bits();
+    if STARTED.load(Ordering::Acquire) & bit == 0 {
+        // Stale IPI, the call was for other harts
+        return;
+    }
+
+    let func = FUNC.load(Ordering::Relaxed);
+    STARTED.fetch_and(!bit, Ordering::Release);
+
+    let func: fn() = unsafe { core::mem::transmute(func) };
+    func();
+    FINISHED.fetch_and(!bit, Ordering::Release);
+}
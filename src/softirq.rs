@@ -0,0 +1,169 @@
+// Softirqs and tasklets, the bottom half of interrupt handling
+//
+// A hard interrupt handler does the least it can with interrupts off and
+// raises a softirq for the rest. Pending softirqs of a hart run when it
+// leaves its outermost interrupt, with interrupts enabled again.
+//
+// Tasklets are run from the Tasklet softirq. A tasklet runs on the hart
+// that scheduled it, and never on two harts at once.
+
+use crate::arch::riscv::{thread_pointer, without_interrupts};
+use crate::hart::MAX_HARTS;
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use riscv::register::sstatus;
+
+/// Softirq vectors, run in this order
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SoftIrq {
+    Timer = 0,
+    Tasklet = 1,
+}
+
+const SOFTIRQS: usize = 2;
+
+/// Rounds of softirqs run on one interrupt exit, the rest waits for the next
+const MAX_RESTART: usize = 10;
+
+const NO_HANDLER: AtomicUsize = AtomicUsize::new(0);
+const NONE_PENDING: AtomicUsize = AtomicUsize::new(0);
+const INACTIVE: AtomicBool = AtomicBool::new(false);
+
+static HANDLERS: [AtomicUsize; SOFTIRQS] = [NO_HANDLER; SOFTIRQS];
+/// Raised softirqs, as bits of `SoftIrq`, per hart
+static PENDING: [AtomicUsize; MAX_HARTS] = [NONE_PENDING; MAX_HARTS];
+/// Set while a hart runs its softirqs
+static ACTIVE: [AtomicBool; MAX_HARTS] = [INACTIVE; MAX_HARTS];
+
+/// Set the handler of a softirq
+pub fn open_softirq(nr: SoftIrq, handler: fn()) {
+    HANDLERS[nr as usize].store(handler as usize, Ordering::Release);
+}
+
+/// Mark a softirq pending on the current hart
+pub fn raise_softirq(nr: SoftIrq) {
+    PENDING[thread_pointer()].fetch_or(1 << nr as usize, Ordering::Relaxed);
+}
+
+/// Whether softirqs are pending on the current hart
+pub fn pending() -> bool {
+    PENDING[thread_pointer()].load(Ordering::Relaxed) != 0
+}
+
+/// Run the pending softirqs of the current hart
+///
+/// Called on interrupt exit with interrupts disabled, enables them while
+/// the handlers run. Does nothing if called from within a softirq.
+pub fn do_softirq() {
+    let hart = thread_pointer();
+    if ACTIVE[hart].swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    for _ in 0..MAX_RESTART {
+        let pending = PENDING[hart].swap(0, Ordering::Relaxed);
+        if pending == 0 {
+            break;
+        }
+
+        unsafe { sstatus::set_sie() };
+        for nr in (0..SOFTIRQS).filter(|nr| pending & 1 << nr != 0) {
+            match HANDLERS[nr].load(Ordering::Acquire) {
+                0 => {}
+                handler => {
+                    let handler: fn() = unsafe { core::mem::transmute(handler) };
+                    handler();
+                }
+            }
+        }
+        unsafe { sstatus::clear_sie() };
+    }
+
+    ACTIVE[hart].store(false, Ordering::Release);
+}
+
+const TASKLET_SCHED: usize = 1 << 0;
+const TASKLET_RUN: usize = 1 << 1;
+
+/// Deferred work run from softirq context
+pub struct Tasklet {
+    func: fn(usize),
+    data: usize,
+    state: AtomicUsize,
+    next: AtomicPtr<Tasklet>,
+}
+
+impl Tasklet {
+    pub const fn new(func: fn(usize), data: usize) -> Self {
+        Self {
+            func,
+            data,
+            state: AtomicUsize::new(0),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn is_scheduled(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & TASKLET_SCHED != 0
+    }
+}
+
+const NO_TASKLET: AtomicPtr<Tasklet> = AtomicPtr::new(ptr::null_mut());
+
+/// Scheduled tasklets per hart, newest first. Only touched by its own hart
+/// with interrupts disabled.
+static TASKLETS: [AtomicPtr<Tasklet>; MAX_HARTS] = [NO_TASKLET; MAX_HARTS];
+
+/// Schedule a tasklet on the current hart
+///
+/// Does nothing if it is already scheduled. A tasklet scheduled while it
+/// runs runs once more.
+pub fn tasklet_schedule(tasklet: &'static Tasklet) {
+    if tasklet.state.fetch_or(TASKLET_SCHED, Ordering::AcqRel) & TASKLET_SCHED == 0 {
+        enqueue(tasklet);
+    }
+}
+
+fn enqueue(tasklet: &'static Tasklet) {
+    without_interrupts(|| {
+        let head = &TASKLETS[thread_pointer()];
+        tasklet
+            .next
+            .store(head.load(Ordering::Relaxed), Ordering::Relaxed);
+        head.store(tasklet as *const _ as *mut _, Ordering::Relaxed);
+        raise_softirq(SoftIrq::Tasklet);
+    });
+}
+
+fn tasklet_action() {
+    let mut list =
+        without_interrupts(|| TASKLETS[thread_pointer()].swap(ptr::null_mut(), Ordering::Relaxed));
+
+    // Oldest first
+    let mut fifo = ptr::null_mut();
+    while let Some(tasklet) = unsafe { list.as_ref() } {
+        let next = tasklet.next.swap(fifo, Ordering::Relaxed);
+        fifo = list;
+        list = next;
+    }
+
+    while let Some(tasklet) = unsafe { (fifo as *const Tasklet).as_ref() } {
+        fifo = tasklet.next.load(Ordering::Relaxed);
+
+        if tasklet.state.fetch_or(TASKLET_RUN, Ordering::Acquire) & TASKLET_RUN != 0 {
+            // Running on another hart, try again later
+            enqueue(tasklet);
+            continue;
+        }
+        tasklet.state.fetch_and(!TASKLET_SCHED, Ordering::AcqRel);
+        (tasklet.func)(tasklet.data);
+        tasklet.state.fetch_and(!TASKLET_RUN, Ordering::Release);
+    }
+}
+
+/// Register the tasklet softirq
+pub fn init() {
+    open_softirq(SoftIrq::Tasklet, tasklet_action);
+}
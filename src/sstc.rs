@@ -0,0 +1,155 @@
+// Sstc, the supervisor timer compare extension
+//
+// With Sstc, S-mode programs its timer event in the stimecmp CSR and takes
+// the timer interrupt itself, as a supervisor timer interrupt (scause 5).
+// Without it, `timervec` takes the mtimecmp interrupt in machine mode and
+// forwards it as a software interrupt.
+//
+// Machine mode tries to turn it on for each hart by setting menvcfg.STCE,
+// which sticks only on harts that have it. S-mode uses it on those harts,
+// unless the device tree lists the extensions of the hart and Sstc is not
+// among them.
+
+use crate::arch::riscv::thread_pointer;
+use crate::fdt;
+use crate::hart::{HartMask, MAX_HARTS};
+
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use log::info;
+use riscv::register::{mcounteren, mhartid};
+
+const NOT_AVAILABLE: AtomicBool = AtomicBool::new(false);
+
+/// Set in machine mode for the harts that have Sstc
+static AVAILABLE: [AtomicBool; MAX_HARTS] = [NOT_AVAILABLE; MAX_HARTS];
+
+/// Harts the device tree lists without Sstc
+static UNLISTED: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" {
+    /// Set menvcfg.STCE, returns whether it stuck
+    fn sstc_probe() -> usize;
+}
+
+// menvcfg may not exist at all, then the access traps. The probe points
+// mtvec at a handler that skips the instruction for as long as it runs.
+global_asm!(
+    r#"
+.pushsection .machine.text, "ax", @progbits
+.global sstc_probe
+.align 4
+sstc_probe:
+    csrr t0, mtvec
+    lla t1, 1f
+    csrw mtvec, t1
+    li a0, 0
+    li t1, 1
+    slli t1, t1, 63
+    # menvcfg
+    csrs 0x30a, t1
+    csrr a0, 0x30a
+    j 2f
+
+.align 4
+1:
+    csrr t1, mepc
+    addi t1, t1, 4
+    csrw mepc, t1
+    mret
+
+2:
+    csrw mtvec, t0
+    srli a0, a0, 63
+    ret
+.popsection
+"#
+);
+
+/// Enable Sstc on the current hart, if it has it
+///
+/// # Safety
+///
+/// Must be called in machine mode, once per hart.
+pub unsafe fn hartinit() {
+    let hart = mhartid::read();
+    if sstc_probe() == 0 {
+        return;
+    }
+
+    // S-mode may only access stimecmp with mcounteren.TM set
+    mcounteren::set_tm();
+    // No event until S-mode programs one
+    asm!("csrw 0x14d, {}", in(reg) usize::MAX);
+    AVAILABLE[hart].store(true, Ordering::Release);
+}
+
+/// Check if the extensions in `riscv,isa` include Sstc
+///
+/// Multi-letter extensions follow the single letter ones, separated by
+/// underscores, like `rv64imafdc_zicsr_sstc`.
+fn isa_has_sstc(isa: &str) -> bool {
+    isa.split('_')
+        .skip(1)
+        .any(|ext| ext.eq_ignore_ascii_case("sstc"))
+}
+
+/// Check the device tree for which harts it says have Sstc
+pub fn init() {
+    let cpus = match fdt::fdt().and_then(|fdt| fdt.find_node("/cpus")) {
+        Some(cpus) => cpus,
+        None => return,
+    };
+
+    let mut unlisted = HartMask::empty();
+    for cpu in cpus
+        .children()
+        .filter(|node| node.name().starts_with("cpu@"))
+    {
+        let hart = match cpu.property("reg").and_then(|reg| reg.as_u32()) {
+            Some(hart) if (hart as usize) < MAX_HARTS => hart as usize,
+            _ => continue,
+        };
+
+        let listed = if let Some(exts) = cpu.property("riscv,isa-extensions") {
+            exts.value().split(|&c| c == 0).any(|ext| ext == b"sstc")
+        } else if let Some(isa) = cpu.property("riscv,isa").and_then(|isa| isa.as_str()) {
+            isa_has_sstc(isa)
+        } else {
+            continue;
+        };
+        if !listed {
+            unlisted.set(hart);
+        }
+    }
+    UNLISTED.store(unlisted.bits(), Ordering::Relaxed);
+
+    if !AVAILABLE
+        .iter()
+        .any(|available| available.load(Ordering::Acquire))
+    {
+        info!("Sstc: not available, timer events go through machine mode");
+    }
+
+    for hart in (0..MAX_HARTS).filter(|&hart| AVAILABLE[hart].load(Ordering::Acquire)) {
+        if unlisted.contains(hart) {
+            info!("Sstc: hart {} has it, but not in the device tree", hart);
+        } else {
+            info!("Sstc: hart {} uses stimecmp", hart);
+        }
+    }
+}
+
+/// Check if the current hart takes its timer events through stimecmp
+pub fn enabled() -> bool {
+    let hart = thread_pointer();
+    AVAILABLE[hart].load(Ordering::Acquire)
+        && !HartMask::from_bits(UNLISTED.load(Ordering::Relaxed)).contains(hart)
+}
+
+/// Raise a supervisor timer interrupt on the current hart once time reaches
+/// `deadline`, replacing the previous event
+pub fn set_next_event(deadline: u64) {
+    unsafe { asm!("csrw 0x14d, {}", in(reg) deadline) };
+}
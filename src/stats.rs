@@ -0,0 +1,197 @@
+// Interrupt and trap statistics
+//
+// Each hart counts the interrupts and exceptions it handled, by PLIC
+// source, interrupt cause and exception cause, along with how long the
+// handlers took in mtime ticks. A hart only updates its own counters, so
+// they need no locking. The time of a handler includes that of the
+// interrupts that preempted it.
+
+use crate::arch::riscv::{self, thread_pointer};
+use crate::hart::{self, MAX_HARTS};
+use crate::plic::{InterruptId, PLIC_SOURCES};
+use crate::print;
+use crate::println;
+use crate::trap::{Trap, SCAUSE_INTERRUPT};
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of interrupt and of exception causes
+const CAUSES: usize = 16;
+
+/// Counters of one kind of event
+struct Counter {
+    count: AtomicUsize,
+    total: AtomicUsize,
+    max: AtomicUsize,
+}
+
+impl Counter {
+    const fn new() -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+            max: AtomicUsize::new(0),
+        }
+    }
+
+    fn record(&self, ticks: usize) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(ticks, Ordering::Relaxed);
+        self.max.fetch_max(ticks, Ordering::Relaxed);
+    }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            count: self.count.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of a counter, times in mtime ticks
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Stat {
+    pub count: usize,
+    /// Time spent in the handler
+    pub total: usize,
+    /// Longest time spent in the handler
+    pub max: usize,
+}
+
+impl Stat {
+    /// Average time spent in the handler
+    pub fn average(&self) -> usize {
+        self.total.checked_div(self.count).unwrap_or(0)
+    }
+
+    fn add(self, other: Stat) -> Stat {
+        Stat {
+            count: self.count + other.count,
+            total: self.total + other.total,
+            max: self.max.max(other.max),
+        }
+    }
+}
+
+const COUNTER: Counter = Counter::new();
+
+struct HartStats {
+    plic: [Counter; PLIC_SOURCES],
+    interrupts: [Counter; CAUSES],
+    exceptions: [Counter; CAUSES],
+}
+
+impl HartStats {
+    const fn new() -> Self {
+        Self {
+            plic: [COUNTER; PLIC_SOURCES],
+            interrupts: [COUNTER; CAUSES],
+            exceptions: [COUNTER; CAUSES],
+        }
+    }
+}
+
+const HART_STATS: HartStats = HartStats::new();
+
+static STATS: [HartStats; MAX_HARTS] = [HART_STATS; MAX_HARTS];
+
+/// Start timing a handler, the value goes to one of the `record` functions
+pub fn start() -> usize {
+    riscv::time()
+}
+
+fn elapsed(start: usize) -> usize {
+    riscv::time().wrapping_sub(start)
+}
+
+/// Count an interrupt from the PLIC source `irq` on the current hart
+pub fn record_plic(irq: u32, start: usize) {
+    if let Some(counter) = STATS[thread_pointer()].plic.get(irq as usize) {
+        counter.record(elapsed(start));
+    }
+}
+
+/// Count an interrupt with the cause `code` on the current hart
+pub fn record_interrupt(code: usize, start: usize) {
+    if let Some(counter) = STATS[thread_pointer()].interrupts.get(code) {
+        counter.record(elapsed(start));
+    }
+}
+
+/// Count an exception with the cause `code` on the current hart
+pub fn record_exception(code: usize, start: usize) {
+    if let Some(counter) = STATS[thread_pointer()].exceptions.get(code) {
+        counter.record(elapsed(start));
+    }
+}
+
+/// Interrupts from `source` handled by `hart`
+pub fn plic(hart: usize, source: InterruptId) -> Stat {
+    STATS[hart].plic[source as usize].stat()
+}
+
+/// Interrupts with the cause `code` handled by `hart`
+pub fn interrupt(hart: usize, code: usize) -> Stat {
+    STATS[hart]
+        .interrupts
+        .get(code)
+        .map(Counter::stat)
+        .unwrap_or_default()
+}
+
+/// Exceptions with the cause `code` handled by `hart`
+pub fn exception(hart: usize, code: usize) -> Stat {
+    STATS[hart]
+        .exceptions
+        .get(code)
+        .map(Counter::stat)
+        .unwrap_or_default()
+}
+
+/// Print a row of the table, if any hart counted something
+fn print_row(kind: char, number: usize, name: fmt::Arguments, stat: impl Fn(usize) -> Stat) {
+    let sum = (0..MAX_HARTS).map(&stat).fold(Stat::default(), Stat::add);
+    if sum.count == 0 {
+        return;
+    }
+
+    print!("{}{:>3}:", kind, number);
+    for hart in hart::online_mask().iter() {
+        print!(" {:>10}", stat(hart).count);
+    }
+    println!(" {:>10} {:>10}  {}", sum.average(), sum.max, name);
+}
+
+/// Print the counters of the started harts, like /proc/interrupts
+///
+/// Rows are PLIC sources, then interrupt causes (I) and exception causes
+/// (E). The last columns are the average and longest time in the handler
+/// across all harts, in mtime ticks.
+pub fn dump() {
+    print!("    ");
+    for hart in hart::online_mask().iter() {
+        print!(" {:>9}{}", "CPU", hart);
+    }
+    println!(" {:>10} {:>10}", "avg", "max");
+
+    for irq in 1..PLIC_SOURCES {
+        let id = InterruptId::from(irq as u32);
+        print_row(' ', irq, format_args!("PLIC {:?}", id), |hart| {
+            STATS[hart].plic[irq].stat()
+        });
+    }
+    for code in 0..CAUSES {
+        let trap = Trap::from(code | SCAUSE_INTERRUPT);
+        print_row('I', code, format_args!("{}", trap), |hart| {
+            interrupt(hart, code)
+        });
+    }
+    for code in 0..CAUSES {
+        let trap = Trap::from(code);
+        print_row('E', code, format_args!("{}", trap), |hart| {
+            exception(hart, code)
+        });
+    }
+}
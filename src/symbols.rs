@@ -1,89 +1,125 @@
 #![allow(non_snake_case)]
 use crate::println;
 
-extern "C" {
-    static _sheap: u8;
-    static _eheap: u8;
-    static _heap_size: u8;
-    static _sbss: u8;
-    static _ebss: u8;
-    static _srodata: u8;
-    static _sdata: u8;
-    static _edata: u8;
-    static _sstack: u8;
-    static _estack: u8;
-    static _stext: u8;
+/// Address of a symbol defined by the linker script or in assembly
+///
+/// Loaded pc-relative, so it works in the position independent build without
+/// going through a GOT.
+macro_rules! symbol {
+    ($name:literal) => {{
+        let addr: usize;
+        unsafe {
+            core::arch::asm!(
+                concat!("lla {}, ", $name),
+                out(reg) addr,
+                options(nomem, nostack)
+            );
+        }
+        addr
+    }};
+}
+pub(crate) use symbol;
+
+/// Value of an absolute symbol defined by the linker script
+///
+/// Absolute symbols can not be loaded pc-relative in a position independent
+/// build. Must fit in 32 bits.
+macro_rules! absolute_symbol {
+    ($name:literal) => {{
+        let value: usize;
+        unsafe {
+            core::arch::asm!(
+                concat!("lui {0}, %hi(", $name, ")"),
+                concat!("addi {0}, {0}, %lo(", $name, ")"),
+                out(reg) value,
+                options(nomem, nostack)
+            );
+        }
+        value
+    }};
 }
 
 pub fn KERNEL_STACK_START() -> usize {
-    unsafe {
-        return &_sstack as *const u8 as usize;
-    }
+    symbol!("_sstack")
 }
 
 pub fn KERNEL_STACK_END() -> usize {
-    unsafe {
-        return &_estack as *const u8 as usize;
-    }
+    symbol!("_estack")
+}
+
+pub fn HART_STACK_SIZE() -> usize {
+    absolute_symbol!("_hart_stack_size")
 }
 
 pub fn HEAP_START() -> usize {
-    unsafe {
-        return &_sheap as *const u8 as usize;
-    }
+    symbol!("_sheap")
 }
 
 pub fn HEAP_END() -> usize {
-    unsafe {
-        return &_eheap as *const u8 as usize;
-    }
+    symbol!("_eheap")
 }
 
 pub fn HEAP_SIZE() -> usize {
-    unsafe {
-        return &_heap_size as *const u8 as usize;
-    }
+    absolute_symbol!("_heap_size")
 }
 
 pub fn TEXT_START() -> usize {
-    unsafe {
-        return &_stext as *const u8 as usize;
-    }
+    symbol!("_stext")
 }
 
 pub fn RODATA_START() -> usize {
-    unsafe {
-        return &_sdata as *const u8 as usize;
-    }
+    symbol!("_sdata")
 }
 
 pub fn RODATA_END() -> usize {
-    unsafe {
-        return &_edata as *const u8 as usize;
-    }
+    symbol!("_edata")
 }
 pub fn DATA_START() -> usize {
-    unsafe {
-        return &_sdata as *const u8 as usize;
-    }
+    symbol!("_sdata")
 }
 
 pub fn DATA_END() -> usize {
-    unsafe {
-        return &_edata as *const u8 as usize;
-    }
+    symbol!("_edata")
 }
 
 pub fn BSS_START() -> usize {
-    unsafe {
-        return &_sbss as *const u8 as usize;
-    }
+    symbol!("_sbss")
 }
 
 pub fn BSS_END() -> usize {
-    unsafe {
-        return &_ebss as *const u8 as usize;
-    }
+    symbol!("_ebss")
+}
+
+pub fn MACHINE_START() -> usize {
+    symbol!("_smachine")
+}
+
+pub fn MACHINE_END() -> usize {
+    symbol!("_emachine")
+}
+
+pub fn RELA_START() -> usize {
+    symbol!("__rela_dyn_start")
+}
+
+pub fn RELA_END() -> usize {
+    symbol!("__rela_dyn_end")
+}
+
+pub fn KSYMS_START() -> usize {
+    symbol!("__ksyms_start")
+}
+
+pub fn KSYMS_END() -> usize {
+    symbol!("__ksyms_end")
+}
+
+pub fn EX_TABLE_START() -> usize {
+    symbol!("__start___ex_table")
+}
+
+pub fn EX_TABLE_END() -> usize {
+    symbol!("__stop___ex_table")
 }
 
 pub fn dump_symbols() {
@@ -93,6 +129,7 @@ pub fn dump_symbols() {
     println!("\tHeap size:          0x{:X}", HEAP_SIZE());
     println!("\tKernel stack start: 0x{:X}", KERNEL_STACK_START());
     println!("\tKernel stack end:   0x{:X}", KERNEL_STACK_END());
+    println!("\tHart stack size:    0x{:X}", HART_STACK_SIZE());
     println!("\tText start:         0x{:X}", TEXT_START());
     println!("\tRO Data start:      0x{:X}", RODATA_START());
     println!("\tRO Data end:        0x{:X}", RODATA_END());
@@ -100,4 +137,12 @@ pub fn dump_symbols() {
     println!("\tData end:           0x{:X}", DATA_END());
     println!("\tBss start:          0x{:X}", BSS_START());
     println!("\tBss end:            0x{:X}", BSS_END());
+    println!("\tMachine start:      0x{:X}", MACHINE_START());
+    println!("\tMachine end:        0x{:X}", MACHINE_END());
+    println!("\tRelocations start:  0x{:X}", RELA_START());
+    println!("\tRelocations end:    0x{:X}", RELA_END());
+    println!("\tSymbol table start: 0x{:X}", KSYMS_START());
+    println!("\tSymbol table end:   0x{:X}", KSYMS_END());
+    println!("\tEx table start:     0x{:X}", EX_TABLE_START());
+    println!("\tEx table end:       0x{:X}", EX_TABLE_END());
 }
@@ -0,0 +1,155 @@
+// The periodic tick and the timer event of each hart
+//
+// The timer event of a hart, in mtimecmp or with Sstc in stimecmp, is
+// programmed for the next periodic tick or the earliest timer deadline,
+// whichever comes first. A hart that goes idle stops its tick and sleeps
+// until its next timer, or until some other interrupt. On the way out of
+// idle it catches up on the ticks it skipped, so `jiffies` and the idle
+// time stay correct.
+
+use crate::arch::riscv::{self, thread_pointer, without_interrupts};
+use crate::clint;
+use crate::clocksource;
+use crate::hart::MAX_HARTS;
+use crate::softirq::{self, SoftIrq};
+use crate::sstc;
+use crate::timer;
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+/// Ticks per second
+pub const HZ: usize = 10;
+
+/// Closest an event is programmed ahead of now, in microseconds
+///
+/// An expired timer is run from the softirq that is raised anyway. Were it
+/// programmed as is, the event would fire again as soon as interrupts are
+/// enabled, and keep the softirq from ever running.
+const MIN_DELTA_US: u64 = 100;
+
+const NO_TICK: AtomicUsize = AtomicUsize::new(usize::MAX);
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+const BUSY: AtomicBool = AtomicBool::new(false);
+
+/// Time of the next tick per hart
+static NEXT_TICK: [AtomicUsize; MAX_HARTS] = [NO_TICK; MAX_HARTS];
+/// Set while the hart is idle with its tick stopped
+static IDLE: [AtomicBool; MAX_HARTS] = [BUSY; MAX_HARTS];
+/// When the hart went idle
+static IDLE_START: [AtomicUsize; MAX_HARTS] = [ZERO; MAX_HARTS];
+/// mtime ticks the hart spent idle
+static IDLE_TIME: [AtomicUsize; MAX_HARTS] = [ZERO; MAX_HARTS];
+
+/// Tick periods since mtime started
+static JIFFIES: AtomicUsize = AtomicUsize::new(0);
+
+/// Period of the tick in mtime ticks
+pub fn period() -> usize {
+    (clocksource::frequency() as usize / HZ).max(1)
+}
+
+pub fn jiffies() -> usize {
+    JIFFIES.load(Ordering::Relaxed)
+}
+
+/// mtime ticks `hart` spent idle
+pub fn idle_time(hart: usize) -> usize {
+    IDLE_TIME[hart].load(Ordering::Relaxed)
+}
+
+fn update_jiffies(now: usize) {
+    JIFFIES.fetch_max(now / period(), Ordering::Relaxed);
+}
+
+fn min_delta() -> usize {
+    clocksource::duration_to_ticks(Duration::from_micros(MIN_DELTA_US)) as usize
+}
+
+/// Program the timer event of the current hart for its next deadline
+pub fn program() {
+    let hart = thread_pointer();
+    without_interrupts(|| {
+        let mut next = timer::next_deadline().unwrap_or(usize::MAX);
+        if !IDLE[hart].load(Ordering::Relaxed) {
+            next = next.min(NEXT_TICK[hart].load(Ordering::Relaxed));
+        }
+        let next = next.max(riscv::time() + min_delta());
+        if sstc::enabled() {
+            sstc::set_next_event(next as u64);
+        } else {
+            clint::set_next_event(next as u64);
+        }
+    });
+}
+
+/// Clear the timer event of the current hart, before it is stopped
+pub fn stop() {
+    let hart = thread_pointer();
+    NEXT_TICK[hart].store(usize::MAX, Ordering::Relaxed);
+    if sstc::enabled() {
+        sstc::set_next_event(u64::MAX);
+    }
+    clint::timer_stop(hart);
+}
+
+/// Handle the timer event of the current hart
+///
+/// Expired timers run from the timer softirq.
+pub fn handle() {
+    let hart = thread_pointer();
+    let now = riscv::time();
+
+    let period = period();
+    let next = NEXT_TICK[hart].load(Ordering::Relaxed);
+    if !IDLE[hart].load(Ordering::Relaxed) && now >= next {
+        // Skip ticks that were missed
+        let missed = (now - next) / period;
+        NEXT_TICK[hart].store(next + (missed + 1) * period, Ordering::Relaxed);
+        update_jiffies(now);
+    }
+
+    softirq::raise_softirq(SoftIrq::Timer);
+    program();
+}
+
+/// Stop the tick of the current hart before it sleeps
+///
+/// Called with interrupts disabled.
+pub fn idle_enter() {
+    let hart = thread_pointer();
+    IDLE_START[hart].store(riscv::time(), Ordering::Relaxed);
+    IDLE[hart].store(true, Ordering::Relaxed);
+    program();
+}
+
+/// Restart the tick of the current hart after it woke up
+///
+/// Called with interrupts disabled.
+pub fn idle_exit() {
+    let hart = thread_pointer();
+    let now = riscv::time();
+    IDLE[hart].store(false, Ordering::Relaxed);
+
+    let start = IDLE_START[hart].load(Ordering::Relaxed);
+    IDLE_TIME[hart].fetch_add(now - start, Ordering::Relaxed);
+    update_jiffies(now);
+
+    NEXT_TICK[hart].store(next_tick(now), Ordering::Relaxed);
+    program();
+}
+
+/// The first tick after `now`, ticks are kept on multiples of the period
+fn next_tick(now: usize) -> usize {
+    let period = period();
+    (now / period + 1) * period
+}
+
+/// Start the tick of the current hart
+pub fn hartinit() {
+    let hart = thread_pointer();
+    let now = riscv::time();
+    IDLE[hart].store(false, Ordering::Relaxed);
+    NEXT_TICK[hart].store(next_tick(now), Ordering::Relaxed);
+    program();
+}
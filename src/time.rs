@@ -0,0 +1,85 @@
+use crate::arch;
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Nanoseconds since the Unix epoch at boot, 0 until synced with the RTC
+static BOOT_REALTIME: AtomicU64 = AtomicU64::new(0);
+
+/// Set the wall clock time from `now`, the current time since the Unix epoch
+pub fn sync_realtime(now: Duration) {
+    let boot = now.saturating_sub(arch::riscv::uptime());
+    BOOT_REALTIME.store(boot.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Wall clock time since the Unix epoch
+///
+/// Kept as an offset from the uptime, so reading it does not touch the RTC.
+/// None until the RTC has been read once.
+pub fn realtime() -> Option<Duration> {
+    match BOOT_REALTIME.load(Ordering::Relaxed) {
+        0 => None,
+        boot => Some(Duration::from_nanos(boot) + arch::riscv::uptime()),
+    }
+}
+
+/// Calendar date and time in UTC
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct DateTime {
+    pub year: u64,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+}
+
+impl From<Duration> for DateTime {
+    /// Convert a time since the Unix epoch
+    fn from(time: Duration) -> Self {
+        let secs = time.as_secs();
+        let (year, month, day) = civil_from_days(secs / SECS_PER_DAY);
+        let secs = secs % SECS_PER_DAY;
+
+        Self {
+            year,
+            month,
+            day,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+            nanosecond: time.subsec_nanos(),
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Year, month and day of the days since 1970-01-01
+///
+/// Howard Hinnant's `civil_from_days`, restricted to dates after the epoch.
+fn civil_from_days(days: u64) -> (u64, u8, u8) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+
+    (year, month as u8, day as u8)
+}
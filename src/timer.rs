@@ -0,0 +1,359 @@
+// Kernel timers
+//
+// A timer calls its function once its deadline, in mtime ticks, has
+// passed, and again every period for a periodic timer. Each hart keeps the
+// timers added on it in a binary heap ordered by deadline, and runs the
+// expired ones from the timer softirq with interrupts enabled.
+//
+// Every `mod_timer` and `del_timer` bumps the sequence number of the timer.
+// A periodic timer is only re-armed after its callback if the number is
+// unchanged, so a timer changed or deleted while its callback runs stays
+// that way.
+//
+// A hart that stops hands its pending timers to another hart with
+// `migrate`.
+
+use crate::arch::riscv::{self, thread_pointer, without_interrupts};
+use crate::hart::MAX_HARTS;
+use crate::ipi::{self, Ipi};
+use crate::tick;
+
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+/// Number of pending timers a hart can hold
+const MAX_TIMERS: usize = 64;
+
+/// `Timer::hart` of a timer that is not pending
+const NO_HART: usize = usize::MAX;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Error {
+    /// The timer heap of the hart is full
+    Full,
+}
+
+pub struct Timer {
+    func: fn(usize),
+    data: usize,
+    /// Deadline in mtime ticks
+    expires: AtomicUsize,
+    /// Period in mtime ticks, 0 for a one-shot timer
+    period: usize,
+    /// Hart whose heap holds the timer, `NO_HART` if not pending
+    hart: AtomicUsize,
+    /// Index in that heap
+    index: AtomicUsize,
+    seq: AtomicUsize,
+}
+
+impl Timer {
+    /// A one-shot timer calling `func` with `data`
+    pub const fn new(func: fn(usize), data: usize) -> Self {
+        Self::periodic(func, data, 0)
+    }
+
+    /// A timer calling `func` with `data` every `period` mtime ticks
+    pub const fn periodic(func: fn(usize), data: usize, period: usize) -> Self {
+        Self {
+            func,
+            data,
+            expires: AtomicUsize::new(0),
+            period,
+            hart: AtomicUsize::new(NO_HART),
+            index: AtomicUsize::new(0),
+            seq: AtomicUsize::new(0),
+        }
+    }
+
+    /// The deadline of the timer, in mtime ticks
+    pub fn expires(&self) -> usize {
+        self.expires.load(Ordering::Relaxed)
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.hart.load(Ordering::Acquire) != NO_HART
+    }
+}
+
+/// Min-heap of the pending timers of a hart
+struct Heap {
+    timers: [*const Timer; MAX_TIMERS],
+    len: usize,
+}
+
+// Only points to `&'static Timer`
+unsafe impl Send for Heap {}
+
+impl Heap {
+    const fn new() -> Self {
+        Self {
+            timers: [ptr::null(); MAX_TIMERS],
+            len: 0,
+        }
+    }
+
+    fn get(&self, index: usize) -> &'static Timer {
+        unsafe { &*self.timers[index] }
+    }
+
+    fn set(&mut self, index: usize, timer: &'static Timer) {
+        self.timers[index] = timer;
+        timer.index.store(index, Ordering::Relaxed);
+    }
+
+    fn peek(&self) -> Option<&'static Timer> {
+        (self.len > 0).then(|| self.get(0))
+    }
+
+    fn push(&mut self, timer: &'static Timer) -> Result<(), Error> {
+        if self.len == MAX_TIMERS {
+            return Err(Error::Full);
+        }
+        self.len += 1;
+        self.set(self.len - 1, timer);
+        self.sift_up(self.len - 1);
+        Ok(())
+    }
+
+    fn remove(&mut self, index: usize) -> &'static Timer {
+        let timer = self.get(index);
+        self.len -= 1;
+        if index != self.len {
+            self.set(index, self.get(self.len));
+            self.sift_up(index);
+            self.sift_down(index);
+        }
+        timer
+    }
+
+    fn sift_up(&mut self, mut index: usize) {
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if self.get(parent).expires() <= self.get(index).expires() {
+                break;
+            }
+            self.swap(index, parent);
+            index = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut index: usize) {
+        loop {
+            let mut first = index;
+            for child in [2 * index + 1, 2 * index + 2] {
+                if child < self.len && self.get(child).expires() < self.get(first).expires() {
+                    first = child;
+                }
+            }
+            if first == index {
+                break;
+            }
+            self.swap(index, first);
+            index = first;
+        }
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        let (timer_a, timer_b) = (self.get(a), self.get(b));
+        self.set(a, timer_b);
+        self.set(b, timer_a);
+    }
+}
+
+const EMPTY_HEAP: Mutex<Heap> = Mutex::new(Heap::new());
+const NOT_RUNNING: AtomicPtr<Timer> = AtomicPtr::new(ptr::null_mut());
+
+/// Pending timers per hart, only locked with interrupts off
+static HEAPS: [Mutex<Heap>; MAX_HARTS] = [EMPTY_HEAP; MAX_HARTS];
+/// The timer whose callback each hart is running
+static RUNNING: [AtomicPtr<Timer>; MAX_HARTS] = [NOT_RUNNING; MAX_HARTS];
+
+/// Take `timer` out of the heap it is in, returns false if it was in none
+fn detach(timer: &'static Timer) -> bool {
+    loop {
+        let hart = timer.hart.load(Ordering::Acquire);
+        if hart == NO_HART {
+            return false;
+        }
+
+        let detached = without_interrupts(|| {
+            let mut heap = HEAPS[hart].lock();
+            // It may have moved before the lock was taken
+            if timer.hart.load(Ordering::Relaxed) != hart {
+                return false;
+            }
+            heap.remove(timer.index.load(Ordering::Relaxed));
+            timer.hart.store(NO_HART, Ordering::Release);
+            true
+        });
+        if detached {
+            return true;
+        }
+    }
+}
+
+/// Add `timer` to the heap of the current hart, unless someone else did
+///
+/// With `seq`, only if the sequence number of the timer still is that.
+fn attach(timer: &'static Timer, expires: usize, seq: Option<usize>) -> Result<bool, Error> {
+    let hart = thread_pointer();
+    without_interrupts(|| {
+        let mut heap = HEAPS[hart].lock();
+        if seq.map_or(false, |seq| timer.seq.load(Ordering::Acquire) != seq) {
+            return Ok(false);
+        }
+        if timer
+            .hart
+            .compare_exchange(NO_HART, hart, Ordering::AcqRel, Ordering::Relaxed)
+            .is_err()
+        {
+            return Ok(false);
+        }
+        timer.expires.store(expires, Ordering::Relaxed);
+        if let Err(err) = heap.push(timer) {
+            timer.hart.store(NO_HART, Ordering::Release);
+            return Err(err);
+        }
+
+        // The timer event of the hart is for a later deadline
+        let first = heap.peek().map_or(false, |first| ptr::eq(first, timer));
+        drop(heap);
+        if first {
+            tick::program();
+        }
+        Ok(true)
+    })
+}
+
+/// Set the deadline of `timer` to `expires`, in mtime ticks
+///
+/// The timer is added on the current hart, and taken off the hart it was
+/// pending on. Returns whether it was pending. Can be called from
+/// interrupt context and from the callback of the timer.
+pub fn mod_timer(timer: &'static Timer, expires: usize) -> Result<bool, Error> {
+    timer.seq.fetch_add(1, Ordering::AcqRel);
+    let mut pending = false;
+    loop {
+        pending |= detach(timer);
+        // Lost against a concurrent `mod_timer`, take theirs out again
+        if attach(timer, expires, None)? {
+            return Ok(pending);
+        }
+    }
+}
+
+/// Start `timer` to expire in `ticks` mtime ticks
+pub fn add_timer(timer: &'static Timer, ticks: usize) -> Result<bool, Error> {
+    mod_timer(timer, riscv::time().wrapping_add(ticks))
+}
+
+/// Stop `timer`, returns whether it was pending
+///
+/// On return the callback is not running on any other hart, and a periodic
+/// timer is not re-armed. Called from the callback itself it does not
+/// wait. Must not be called from an interrupt that may have preempted the
+/// callback on another hart with interrupts disabled.
+pub fn del_timer(timer: &'static Timer) -> bool {
+    timer.seq.fetch_add(1, Ordering::AcqRel);
+    let pending = detach(timer);
+
+    let hart = thread_pointer();
+    let running = timer as *const Timer as *mut Timer;
+    for other in (0..MAX_HARTS).filter(|&other| other != hart) {
+        while RUNNING[other].load(Ordering::Acquire) == running {
+            spin_loop();
+        }
+    }
+    // A callback on another hart may have re-armed it before it saw the
+    // new sequence number
+    detach(timer);
+    pending
+}
+
+/// Move the pending timers of hart `from` to hart `to`
+///
+/// Called by `from` before it stops. Returns `Error::Full` if the timers
+/// did not all fit, the rest stay on `from`.
+pub fn migrate(from: usize, to: usize) -> Result<(), Error> {
+    if from == to {
+        return Ok(());
+    }
+
+    let (moved, result) = without_interrupts(|| {
+        // In hart order, against a migration the other way
+        let (mut source, mut target) = if from < to {
+            let source = HEAPS[from].lock();
+            (source, HEAPS[to].lock())
+        } else {
+            let target = HEAPS[to].lock();
+            (HEAPS[from].lock(), target)
+        };
+
+        let mut moved = 0;
+        while let Some(timer) = source.peek() {
+            if target.len == MAX_TIMERS {
+                return (moved, Err(Error::Full));
+            }
+            source.remove(0);
+            // Can not fail, there is room
+            let _ = target.push(timer);
+            timer.hart.store(to, Ordering::Release);
+            moved += 1;
+        }
+        (moved, Ok(()))
+    });
+
+    // The timer event of `to` may be for a later deadline
+    if moved > 0 {
+        ipi::send(to, Ipi::Timer);
+    }
+    result
+}
+
+/// Take the first expired timer of the current hart off its heap
+///
+/// Returns it with its sequence number at that time.
+fn next_expired(hart: usize, now: usize) -> Option<(&'static Timer, usize)> {
+    without_interrupts(|| {
+        let mut heap = HEAPS[hart].lock();
+        let timer = heap.peek().filter(|timer| timer.expires() <= now)?;
+        heap.remove(0);
+        let seq = timer.seq.load(Ordering::Acquire);
+        timer.hart.store(NO_HART, Ordering::Release);
+        RUNNING[hart].store(timer as *const Timer as *mut Timer, Ordering::Release);
+        Some((timer, seq))
+    })
+}
+
+/// Run the expired timers of the current hart
+///
+/// The timer softirq, runs with interrupts enabled.
+pub fn run_timers() {
+    let hart = thread_pointer();
+    let now = riscv::time();
+
+    while let Some((timer, seq)) = next_expired(hart, now) {
+        (timer.func)(timer.data);
+
+        if timer.period != 0 {
+            // Skip the periods that were missed
+            let mut expires = timer.expires().wrapping_add(timer.period);
+            if expires <= now {
+                expires = now.wrapping_add(timer.period);
+            }
+            // Not if the timer was changed or deleted meanwhile
+            let _ = attach(timer, expires, Some(seq));
+        }
+        RUNNING[hart].store(ptr::null_mut(), Ordering::Release);
+    }
+}
+
+/// The earliest deadline of the current hart, in mtime ticks
+pub fn next_deadline() -> Option<usize> {
+    let hart = thread_pointer();
+    without_interrupts(|| HEAPS[hart].lock().peek().map(Timer::expires))
+}
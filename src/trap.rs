@@ -1,12 +1,20 @@
 use crate::arch;
+use crate::backtrace;
+use crate::extable;
+use crate::hart::MAX_HARTS;
 use crate::interrupt;
+use crate::stats;
+use crate::symbols::symbol;
+use crate::{print, println};
 
 use core::arch::{asm, global_asm};
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use log::info;
 use riscv::register;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Trap {
     UserSoftwareInterrupt,
     SupervisorSoftwareInterrupt,
@@ -35,89 +43,296 @@ pub enum Trap {
     Reserved,
 }
 
-#[no_mangle]
-extern "C" fn machine_trap() {
-    let epc = register::sepc::read();
-    let tval = register::stval::read();
-    let cause = register::scause::read();
+impl From<usize> for Trap {
+    /// Decode the value of scause
+    fn from(scause: usize) -> Self {
+        let code = scause & !SCAUSE_INTERRUPT;
+        if scause & SCAUSE_INTERRUPT != 0 {
+            match code {
+                0 => Trap::UserSoftwareInterrupt,
+                1 => Trap::SupervisorSoftwareInterrupt,
+                3 => Trap::MachineSofrwareInterrupt,
+                4 => Trap::UserTimerInterrupt,
+                5 => Trap::SupervisorTimerInterrupt,
+                7 => Trap::MachineTimerInterrupt,
+                8 => Trap::UserExternalInterrupt,
+                9 => Trap::SupervisorExternalInterrupt,
+                11 => Trap::MachineExternalInterrupt,
+                _ => Trap::Reserved,
+            }
+        } else {
+            match code {
+                0 => Trap::InstructionAddressMisaligned,
+                1 => Trap::InstructionAccesFault,
+                2 => Trap::IllegalInstruction,
+                3 => Trap::Breakpoint,
+                4 => Trap::LoadAddressMisaligned,
+                5 => Trap::LoadAccessFault,
+                6 => Trap::StoreAddressMisaligned,
+                7 => Trap::StoreAccessFault,
+                8 => Trap::UserModeEnvironmentCall,
+                9 => Trap::SupervisorModeEvironmentCall,
+                11 => Trap::MachineModeEnvironmentCall,
+                12 => Trap::InstructionPageFault,
+                13 => Trap::LoadPageFault,
+                15 => Trap::StorePageFault,
+                _ => Trap::Reserved,
+            }
+        }
+    }
+}
+
+impl Trap {
+    pub const fn is_interrupt(&self) -> bool {
+        matches!(
+            self,
+            Trap::UserSoftwareInterrupt
+                | Trap::SupervisorSoftwareInterrupt
+                | Trap::MachineSofrwareInterrupt
+                | Trap::UserTimerInterrupt
+                | Trap::SupervisorTimerInterrupt
+                | Trap::MachineTimerInterrupt
+                | Trap::UserExternalInterrupt
+                | Trap::SupervisorExternalInterrupt
+                | Trap::MachineExternalInterrupt
+        )
+    }
+
+    /// Index in the handler table, exceptions only
+    const fn handler_index(&self) -> Option<usize> {
+        let index = match self {
+            Trap::InstructionAddressMisaligned => 0,
+            Trap::InstructionAccesFault => 1,
+            Trap::IllegalInstruction => 2,
+            Trap::Breakpoint => 3,
+            Trap::LoadAddressMisaligned => 4,
+            Trap::LoadAccessFault => 5,
+            Trap::StoreAddressMisaligned => 6,
+            Trap::StoreAccessFault => 7,
+            Trap::UserModeEnvironmentCall => 8,
+            Trap::SupervisorModeEvironmentCall => 9,
+            Trap::MachineModeEnvironmentCall => 11,
+            Trap::InstructionPageFault => 12,
+            Trap::LoadPageFault => 13,
+            Trap::StorePageFault => 15,
+            _ => return None,
+        };
+        Some(index)
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Trap::UserSoftwareInterrupt => "User software interrupt",
+            Trap::SupervisorSoftwareInterrupt => "Supervisor software interrupt",
+            Trap::MachineSofrwareInterrupt => "Machine software interrupt",
+            Trap::UserTimerInterrupt => "User timer interrupt",
+            Trap::SupervisorTimerInterrupt => "Supervisor timer interrupt",
+            Trap::MachineTimerInterrupt => "Machine timer interrupt",
+            Trap::UserExternalInterrupt => "User external interrupt",
+            Trap::SupervisorExternalInterrupt => "Supervisor external interrupt",
+            Trap::MachineExternalInterrupt => "Machine external interrupt",
+            Trap::InstructionAddressMisaligned => "Instruction address misaligned",
+            Trap::InstructionAccesFault => "Instruction access fault",
+            Trap::IllegalInstruction => "Illegal instruction",
+            Trap::Breakpoint => "Breakpoint",
+            Trap::LoadAddressMisaligned => "Load address misaligned",
+            Trap::LoadAccessFault => "Load access fault",
+            Trap::StoreAddressMisaligned => "Store address misaligned",
+            Trap::StoreAccessFault => "Store access fault",
+            Trap::UserModeEnvironmentCall => "Environment call from user mode",
+            Trap::SupervisorModeEvironmentCall => "Environment call from supervisor mode",
+            Trap::MachineModeEnvironmentCall => "Environment call from machine mode",
+            Trap::InstructionPageFault => "Instruction page fault",
+            Trap::LoadPageFault => "Load page fault",
+            Trap::StorePageFault => "Store page fault",
+            Trap::Reserved => "Reserved",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Handler for an exception
+///
+/// Returns false if the handler could not deal with the trap, which then
+/// ends up in `oops`.
+pub type Handler = fn(&mut TrapFrame) -> bool;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Error {
+    /// Only exceptions can be claimed, interrupts go through `interrupt`
+    NotAnException,
+    /// Another handler already claimed the trap
+    Claimed,
+}
+
+/// Number of exception codes
+const EXCEPTIONS: usize = 16;
+
+const UNCLAIMED: AtomicUsize = AtomicUsize::new(0);
+
+/// Handlers indexed by exception code, 0 if unclaimed
+static HANDLERS: [AtomicUsize; EXCEPTIONS] = [UNCLAIMED; EXCEPTIONS];
+
+/// Claim the exception `trap`
+pub fn register_handler(trap: Trap, handler: Handler) -> Result<(), Error> {
+    let index = trap.handler_index().ok_or(Error::NotAnException)?;
+    HANDLERS[index]
+        .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map(|_| ())
+        .map_err(|_| Error::Claimed)
+}
+
+/// Release the exception `trap`, unclaimed exceptions end up in `oops`
+pub fn unregister_handler(trap: Trap) {
+    if let Some(index) = trap.handler_index() {
+        HANDLERS[index].store(0, Ordering::Release);
+    }
+}
+
+fn handler(trap: Trap) -> Option<Handler> {
+    let index = trap.handler_index()?;
+    match HANDLERS[index].load(Ordering::Acquire) {
+        0 => None,
+        handler => Some(unsafe { core::mem::transmute::<usize, Handler>(handler) }),
+    }
+}
+
+/// Report a trap nobody could handle and stop
+pub fn oops(trap: Trap, frame: &TrapFrame) -> ! {
     let hart = arch::riscv::thread_pointer();
-    let status = register::sstatus::read();
-    let mut sstatus_bits: usize;
-    unsafe {
-        asm!("csrr {}, sstatus", out(reg) sstatus_bits);
+    println!(
+        "Oops: {} on hart {} at 0x{:08x}, stval 0x{:08x}",
+        trap, hart, frame.sepc, frame.stval
+    );
+    print!("{}", frame);
+    backtrace::backtrace_from(frame.sepc, frame.regs[reg::FP]);
+    panic!("{} CPU#{} -> 0x{:08x}", trap, hart, frame.sepc);
+}
+
+/// Indices of the registers in `TrapFrame::regs`
+pub mod reg {
+    pub const RA: usize = 1;
+    pub const SP: usize = 2;
+    pub const GP: usize = 3;
+    pub const TP: usize = 4;
+    pub const FP: usize = 8;
+    pub const A0: usize = 10;
+}
+
+/// ABI names of x0-x31
+const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// Registers of the interrupted code, saved by `_start_trap`
+///
+/// Changes made by the handler are restored on return, setting `sepc`
+/// changes where execution resumes.
+#[repr(C, align(16))]
+#[derive(Debug, Clone)]
+pub struct TrapFrame {
+    /// x0-x31, x0 is always 0
+    pub regs: [usize; 32],
+    pub sepc: usize,
+    pub sstatus: usize,
+    pub stval: usize,
+    pub scause: usize,
+}
+
+impl TrapFrame {
+    pub const fn reg(&self, index: usize) -> usize {
+        self.regs[index]
     }
 
-    if status.spp() != register::sstatus::SPP::Supervisor {
-        panic!("not from supervisor mode,  hart {}", hart);
+    /// Set register `index`, writes to x0 are ignored
+    pub fn set_reg(&mut self, index: usize, value: usize) {
+        if index != 0 {
+            self.regs[index] = value;
+        }
+    }
+
+    /// Argument register a`n`
+    pub const fn arg(&self, n: usize) -> usize {
+        self.regs[reg::A0 + n]
+    }
+
+    /// Set the return value in a0
+    pub fn set_return(&mut self, value: usize) {
+        self.regs[reg::A0] = value;
+    }
+
+    pub const fn sp(&self) -> usize {
+        self.regs[reg::SP]
+    }
+
+    /// Check if the trap is an interrupt, else it is an exception
+    pub const fn is_interrupt(&self) -> bool {
+        self.scause & SCAUSE_INTERRUPT != 0
     }
 
+    /// Interrupt or exception code
+    pub const fn code(&self) -> usize {
+        self.scause & !SCAUSE_INTERRUPT
+    }
+
+    /// Check if the trap was taken from S-mode
+    pub const fn from_kernel(&self) -> bool {
+        self.sstatus & SSTATUS_SPP != 0
+    }
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "sepc: {:016x} sstatus: {:016x} stval: {:016x} scause: {:016x}\r\n",
+            self.sepc, self.sstatus, self.stval, self.scause
+        )?;
+        for (i, chunk) in self.regs.chunks(4).enumerate() {
+            for (j, value) in chunk.iter().enumerate() {
+                write!(f, "{:>4}: {:016x} ", REG_NAMES[i * 4 + j], value)?;
+            }
+            write!(f, "\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+const SSTATUS_SPP: usize = 1 << 8;
+const SSTATUS_SPIE: usize = 1 << 5;
+pub(crate) const SCAUSE_INTERRUPT: usize = 1 << 63;
+
+#[no_mangle]
+extern "C" fn machine_trap(frame: &mut TrapFrame) {
+    let start = stats::start();
+
+    // User memory stays off limits in the handler, even if the trap hit
+    // a user access. sstatus is restored from the frame on return.
     unsafe {
         register::sstatus::clear_sie();
+        register::sstatus::clear_sum();
     }
 
     if arch::riscv::intr_get() {
         panic!("interrupt not disabled");
     }
 
-    if cause.is_interrupt() {
+    if frame.is_interrupt() {
         // handle device interrupt from PLIC
-        interrupt::handle_interrupt(cause.code() as u32);
+        interrupt::handle_interrupt(frame.code() as u32);
     } else {
         // handle synchronous interrupt or exception
-        match cause.code() {
-            0 => panic!(
-                "Instruction address misaligned CPU#{} -> 0x{:08x}: 0x{:08x}",
-                hart, epc, tval
-            ),
-            1 => panic!("Instruction access fault CPU#{}", hart),
-            2 => panic!(
-                "Illegal instruction CPU#{} -> 0x{:08x}: 0x{:08x}",
-                hart, epc, tval
-            ),
-            3 => panic!("Breakpoint CPU#{}", hart),
-            4 => panic!("Load address misaligned CPU#{}", hart),
-            5 => panic!("Load access fault CPU#{}", hart),
-            8 => panic!(
-                "Enviroment call from user mode CPU#{} -> 0x{:08x}",
-                hart, epc
-            ),
-            9 => panic!(
-                "Enviroment call from Supervisor mode CPU#{} -> 0x{:08x}",
-                hart, epc
-            ),
-            11 => panic!(
-                "Enviroment call from Machine mode CPU#{} -> 0x{:08x}",
-                hart, epc
-            ),
-            12 => panic!(
-                "Instruction Page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
-                hart, epc, tval
-            ),
-            13 => panic!(
-                "Load Page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
-                hart, epc, tval
-            ),
-            15 => panic!(
-                "Store Page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
-                hart, epc, tval
-            ),
-            _ => panic!(
-                "Unhandled sync trap {}. CPU#{} -> 0x{:08x}: 0x{:08x}",
-                cause.code(),
-                hart,
-                epc,
-                tval
-            ),
+        let trap = Trap::from(frame.scause);
+        match handler(trap) {
+            Some(handler) if handler(frame) => {}
+            _ if extable::fixup_exception(frame) => {}
+            _ => oops(trap, frame),
         }
-    }
-
-    unsafe {
-        register::sstatus::set_sie();
-    }
-
-    register::sepc::write(epc);
-    unsafe {
-        asm!("csrw sstatus, {}", in(reg) sstatus_bits);
+        stats::record_exception(frame.code(), start);
     }
 }
 
@@ -135,90 +350,199 @@ pub unsafe fn enable_interrupts() {
     register::sie::set_stimer();
 }
 
+/// Disable interrupts
+///
+/// Turns off every supervisor interrupt source, so that `wfi` only wakes up
+/// for machine mode interrupts. Undone by `enable_interrupts`.
+pub unsafe fn disable_interrupts() {
+    register::sstatus::clear_sie();
+    register::sie::clear_ssoft();
+    register::sie::clear_sext();
+    register::sie::clear_stimer();
+    arch::riscv::clear_sie_ssoft();
+}
+
 /// Hart init
 ///
 /// Set the vector for handling supervisor mode
 pub unsafe fn hartinit() {
-    register::stvec::write(_start_trap as usize, register::stvec::TrapMode::Direct);
+    register::sscratch::write(0);
+    register::stvec::write(symbol!("_start_trap"), register::stvec::TrapMode::Direct);
 }
 
+/// Per hart state for traps from user mode, see `_start_trap`
+///
+/// Holds the kernel stack to trap on, the hart id and room to save t0.
+#[no_mangle]
+static mut TRAP_SCRATCH: [[usize; 4]; MAX_HARTS] = [[0; 4]; MAX_HARTS];
+
 extern "C" {
-    fn _start_trap();
+    /// Return from a trap with the frame at `sp`
+    fn __trap_return() -> !;
+}
+
+/// Drop to user mode at `entry`, with the stack pointer `sp`
+///
+/// Traps from user mode are handled on the kernel stack of the caller,
+/// which is never returned to.
+pub unsafe fn enter_user(entry: usize, sp: usize) -> ! {
+    register::sstatus::clear_sie();
+
+    let sstatus: usize;
+    asm!("csrr {}, sstatus", out(reg) sstatus);
+    let mut frame = TrapFrame {
+        regs: [0; 32],
+        sepc: entry,
+        // Interrupts come back on with sret
+        sstatus: (sstatus & !SSTATUS_SPP) | SSTATUS_SPIE,
+        stval: 0,
+        scause: 0,
+    };
+    frame.regs[reg::SP] = sp;
+
+    asm!(
+        "mv sp, {frame}",
+        "j __trap_return",
+        frame = in(reg) &mut frame as *mut TrapFrame,
+        options(noreturn)
+    );
 }
 
 global_asm!(
     r#"
+# Layout of TrapFrame: x0-x31 at 8 * n, then sepc, sstatus, stval, scause
 .global _start_trap
 .align 4
+#
+# sscratch is 0 in the kernel. In user mode it points to the TRAP_SCRATCH
+# entry of the hart: the kernel stack to trap on, and the hart id for tp.
 _start_trap:
-    addi sp, sp, -256
-
-    sd ra, 0(sp)
-    sd sp, 8(sp)
-    sd gp, 16(sp)
-    sd tp, 24(sp)
-    sd t0, 32(sp)
-    sd t1, 40(sp)
-    sd t2, 48(sp)
-    sd s0, 56(sp)
-    sd s1, 64(sp)
-    sd a0, 72(sp)
-    sd a1, 80(sp)
-    sd a2, 88(sp)
-    sd a3, 96(sp)
-    sd a4, 104(sp)
-    sd a5, 112(sp)
-    sd a6, 120(sp)
-    sd a7, 128(sp)
-    sd s2, 136(sp)
-    sd s3, 144(sp)
-    sd s4, 152(sp)
-    sd s5, 160(sp)
-    sd s6, 168(sp)
-    sd s7, 176(sp)
-    sd s8, 184(sp)
-    sd s9, 192(sp)
-    sd s10, 200(sp)
-    sd s11, 208(sp)
-    sd t3, 216(sp)
-    sd t4, 224(sp)
-    sd t5, 232(sp)
-    sd t6, 240(sp)
+    csrrw sp, sscratch, sp
+    bnez sp, 1f
+
+    # From the kernel, stay on the current stack
+    csrrw sp, sscratch, sp
+    addi sp, sp, -288
+    sd tp, 32(sp)
+    sd t0, 40(sp)
+    # sp as it was before the trap
+    addi t0, sp, 288
+    sd t0, 16(sp)
+    j 2f
+
+1:
+    # From user mode, sp is the scratch entry and sscratch the user sp
+    sd t0, 16(sp)
+    mv t0, sp
+    ld sp, 0(t0)
+    addi sp, sp, -288
+    sd tp, 32(sp)
+    ld tp, 8(t0)
+    ld t0, 16(t0)
+    sd t0, 40(sp)
+    # Back in the kernel, later traps stay on this stack
+    csrrw t0, sscratch, zero
+    sd t0, 16(sp)
+
+2:
+    # x0 is part of the frame too, handlers index regs by register number
+    sd zero, 0(sp)
+    sd ra, 8(sp)
+    sd gp, 24(sp)
+    sd t1, 48(sp)
+    sd t2, 56(sp)
+    sd s0, 64(sp)
+    sd s1, 72(sp)
+    sd a0, 80(sp)
+    sd a1, 88(sp)
+    sd a2, 96(sp)
+    sd a3, 104(sp)
+    sd a4, 112(sp)
+    sd a5, 120(sp)
+    sd a6, 128(sp)
+    sd a7, 136(sp)
+    sd s2, 144(sp)
+    sd s3, 152(sp)
+    sd s4, 160(sp)
+    sd s5, 168(sp)
+    sd s6, 176(sp)
+    sd s7, 184(sp)
+    sd s8, 192(sp)
+    sd s9, 200(sp)
+    sd s10, 208(sp)
+    sd s11, 216(sp)
+    sd t3, 224(sp)
+    sd t4, 232(sp)
+    sd t5, 240(sp)
+    sd t6, 248(sp)
+
+    csrr t0, sepc
+    sd t0, 256(sp)
+    csrr t0, sstatus
+    sd t0, 264(sp)
+    csrr t0, stval
+    sd t0, 272(sp)
+    csrr t0, scause
+    sd t0, 280(sp)
 
+    mv a0, sp
     call machine_trap
 
-    ld ra, 0(sp)
-    ld sp, 8(sp)
-    ld gp, 16(sp)
-    ld t0, 32(sp)
-    ld t1, 40(sp)
-    ld t2, 48(sp)
-    ld s0, 56(sp)
-    ld s1, 64(sp)
-    ld a0, 72(sp)
-    ld a1, 80(sp)
-    ld a2, 88(sp)
-    ld a3, 96(sp)
-    ld a4, 104(sp)
-    ld a5, 112(sp)
-    ld a6, 120(sp)
-    ld a7, 128(sp)
-    ld s2, 136(sp)
-    ld s3, 144(sp)
-    ld s4, 152(sp)
-    ld s5, 160(sp)
-    ld s6, 168(sp)
-    ld s7, 176(sp)
-    ld s8, 184(sp)
-    ld s9, 192(sp)
-    ld s10, 200(sp)
-    ld s11, 208(sp)
-    ld t3, 216(sp)
-    ld t4, 224(sp)
-    ld t5, 232(sp)
-    ld t6, 240(sp)
-
-    addi sp, sp, 256
+.global __trap_return
+__trap_return:
+    # The handler may have changed where and how to return
+    ld t0, 256(sp)
+    csrw sepc, t0
+    ld t0, 264(sp)
+    csrw sstatus, t0
+
+    # sret goes to the mode in sstatus.SPP. For user mode, arm sscratch so
+    # that the next trap lands where this frame is.
+    andi t0, t0, 1 << 8
+    bnez t0, 3f
+    lla t0, TRAP_SCRATCH
+    slli t1, tp, 5
+    add t0, t0, t1
+    addi t1, sp, 288
+    sd t1, 0(t0)
+    sd tp, 8(t0)
+    csrw sscratch, t0
+
+3:
+
+    ld ra, 8(sp)
+    ld gp, 24(sp)
+    ld tp, 32(sp)
+    ld t0, 40(sp)
+    ld t1, 48(sp)
+    ld t2, 56(sp)
+    ld s0, 64(sp)
+    ld s1, 72(sp)
+    ld a0, 80(sp)
+    ld a1, 88(sp)
+    ld a2, 96(sp)
+    ld a3, 104(sp)
+    ld a4, 112(sp)
+    ld a5, 120(sp)
+    ld a6, 128(sp)
+    ld a7, 136(sp)
+    ld s2, 144(sp)
+    ld s3, 152(sp)
+    ld s4, 160(sp)
+    ld s5, 168(sp)
+    ld s6, 176(sp)
+    ld s7, 184(sp)
+    ld s8, 192(sp)
+    ld s9, 200(sp)
+    ld s10, 208(sp)
+    ld s11, 216(sp)
+    ld t3, 224(sp)
+    ld t4, 232(sp)
+    ld t5, 240(sp)
+    ld t6, 248(sp)
+
+    # Last, it is the base of the frame
+    ld sp, 16(sp)
 
     sret
 "#
@@ -226,29 +550,105 @@ _start_trap:
 
 global_asm!(
     r#"
+.pushsection .machine.text, "ax", @progbits
 .global timervec
 .align 4
 timervec:
     # scratch[0, 8, 16] : register save area
     # scratch[24] : address of MTIMECMP
-    # scratch[32] : desired interval between interrupts
+    # scratch[32] : address of the watchdog snapshot of the hart
+    # scratch[40] : address of MSIP
+    # scratch[48] : address of the tick flag, read by `clint::take_tick`
 
     csrrw a0, mscratch, a0
     sd a1, 0(a0)
     sd a2, 8(a0)
     sd a3, 16(a0)
 
-    # schedule next timer interrupt
-    # this is done by adding `interval` to mtimecmp
+    # environment call from S-mode, see `sbi`
+    csrr a1, mcause
+    li a2, 9
+    beq a1, a2, 3f
+
+    # machine software interrupt, an IPI or a wake up of a parked hart
+    csrr a1, mcause
+    slli a1, a1, 1
+    li a2, 3 << 1
+    bne a1, a2, 1f
+    ld a1, 40(a0) # MSIP current hart
+    sw zero, 0(a1)
+
+    # the watchdog asks for the registers, see `watchdog::dump_hart`
+    ld a1, 32(a0)
+    ld a2, 0(a1)
+    beqz a2, 2f
+    sd zero, 0(a1)
+    # TrapFrame, after the request and done flags
+    addi a2, a1, 16
+    sd x1, 8(a2)
+    sd x2, 16(a2)
+    sd x3, 24(a2)
+    sd x4, 32(a2)
+    sd x5, 40(a2)
+    sd x6, 48(a2)
+    sd x7, 56(a2)
+    sd x8, 64(a2)
+    sd x9, 72(a2)
+    sd x14, 112(a2)
+    sd x15, 120(a2)
+    sd x16, 128(a2)
+    sd x17, 136(a2)
+    sd x18, 144(a2)
+    sd x19, 152(a2)
+    sd x20, 160(a2)
+    sd x21, 168(a2)
+    sd x22, 176(a2)
+    sd x23, 184(a2)
+    sd x24, 192(a2)
+    sd x25, 200(a2)
+    sd x26, 208(a2)
+    sd x27, 216(a2)
+    sd x28, 224(a2)
+    sd x29, 232(a2)
+    sd x30, 240(a2)
+    sd x31, 248(a2)
+    # a0-a3 were saved on the way in
+    csrr a3, mscratch
+    sd a3, 80(a2)
+    ld a3, 0(a0)
+    sd a3, 88(a2)
+    ld a3, 8(a0)
+    sd a3, 96(a2)
+    ld a3, 16(a0)
+    sd a3, 104(a2)
+    csrr a3, mepc
+    sd a3, 256(a2)
+    csrr a3, sstatus
+    sd a3, 264(a2)
+    csrr a3, stval
+    sd a3, 272(a2)
+    csrr a3, scause
+    sd a3, 280(a2)
+    fence rw, rw
+    li a3, 1
+    sd a3, 8(a1)
+    j 2f
+
+1:
+    # clear the timer event, S-mode programs the next one
     ld a1, 24(a0) # MTIMECMP current hart
-    ld a2, 32(a0) # interval
-    ld a3, 0(a1)
-    add a3, a3, a2
-    sd a3, 0(a1)
+    li a2, -1
+    sd a2, 0(a1)
+
+    # tell S-mode this one is a tick
+    ld a1, 48(a0)
+    li a2, 1
+    sd a2, 0(a1)
 
+2:
     # raise supervisor software interrupt
     li a1, 0x02
-    csrw sip, a1
+    csrs sip, a1
 
     ld a3, 16(a0)
     ld a2, 8(a0)
@@ -256,5 +656,50 @@ timervec:
     csrrw a0, mscratch, a0
 
     mret
+
+3:
+    # a7 is the extension, a6 the function, the caller's a0 is in mscratch.
+    # Error goes in a1 and value in a2 until they are returned in a0 and a1.
+    csrr a1, mepc
+    addi a1, a1, 4
+    csrw mepc, a1
+    li a1, -2 # SBI_ERR_NOT_SUPPORTED
+    li a2, 0
+
+    li a3, 0x4442434e # debug console
+    bne a7, a3, 5f
+    li a3, 2 # console_write_byte
+    bne a6, a3, 6f
+    li a2, 0x10000000 # UART0
+4:
+    lbu a3, 5(a2) # LSR
+    andi a3, a3, 0x20 # THRE
+    beqz a3, 4b
+    csrr a3, mscratch
+    sb a3, 0(a2)
+    li a1, 0
+    li a2, 0
+    j 6f
+
+5:
+    li a3, 0x10 # base
+    bne a7, a3, 6f
+    li a3, 3 # probe_extension
+    bne a6, a3, 6f
+    li a1, 0
+    csrr a3, mscratch
+    li a2, 0x4442434e
+    sub a2, a3, a2
+    seqz a2, a2
+
+6:
+    csrw mscratch, a1
+    sd a2, 0(a0)
+    ld a3, 16(a0)
+    ld a2, 8(a0)
+    ld a1, 0(a0)
+    csrrw a0, mscratch, a0
+    mret
+.popsection
 "#
 );
@@ -0,0 +1,217 @@
+// Access to user memory from the kernel
+//
+// S-mode can only touch user pages with sstatus.SUM set, which is done for
+// the duration of each access. The range is checked against the page table
+// first, and the loads and stores have exception table entries, so a bad
+// user pointer makes the access fail with EFAULT instead of an oops.
+
+use crate::cmdline;
+use crate::ex_table;
+use crate::mem;
+use crate::page::{self, Attribute, PAGE_SIZE};
+
+use core::arch::global_asm;
+use core::mem::{size_of, MaybeUninit};
+
+use log::{info, warn};
+use riscv::register::{satp, sstatus};
+
+/// End of the user half of the Sv39 address space
+pub const USER_END: usize = 1 << 38;
+
+/// Bad address
+pub const EFAULT: isize = 14;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Error {
+    /// The user memory could not be accessed, EFAULT
+    Fault,
+}
+
+impl Error {
+    /// The negated errno value, as returned by system calls
+    pub const fn errno(self) -> isize {
+        match self {
+            Self::Fault => -EFAULT,
+        }
+    }
+}
+
+/// Types that any bit pattern is a valid value of
+///
+/// # Safety
+///
+/// Only for types without padding and invalid values.
+pub unsafe trait Pod: Copy {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for usize {}
+unsafe impl Pod for i8 {}
+unsafe impl Pod for i16 {}
+unsafe impl Pod for i32 {}
+unsafe impl Pod for i64 {}
+unsafe impl Pod for isize {}
+
+extern "C" {
+    /// Copy `len` bytes, returns the number of bytes left when it faulted
+    fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+}
+
+global_asm!(
+    r#"
+.global __copy_user
+.align 4
+__copy_user:
+    beqz a2, 3f
+1:
+    lb t0, 0(a1)
+2:
+    sb t0, 0(a0)
+    addi a0, a0, 1
+    addi a1, a1, 1
+    addi a2, a2, -1
+    bnez a2, 1b
+3:
+    mv a0, a2
+    ret
+"#,
+    ex_table!("1b", "3b"),
+    ex_table!("2b", "3b"),
+);
+
+/// Check that `len` bytes at `addr` are in user pages, writable for `write`
+pub fn access_ok(addr: usize, len: usize, write: bool) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) if end <= USER_END => end,
+        _ => return false,
+    };
+    // The kernel is mapped below USER_END as well, so check every page
+    (addr & !(PAGE_SIZE - 1)..end)
+        .step_by(PAGE_SIZE)
+        .all(|page| mem::user_page(page, write))
+}
+
+/// Copy with user memory accessible
+fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), Error> {
+    let sum = sstatus::read().sum();
+    let left = unsafe {
+        sstatus::set_sum();
+        let left = __copy_user(dst, src, len);
+        if !sum {
+            sstatus::clear_sum();
+        }
+        left
+    };
+
+    match left {
+        0 => Ok(()),
+        _ => Err(Error::Fault),
+    }
+}
+
+/// Copy `dst.len()` bytes from the user address `src`
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), Error> {
+    if !access_ok(src, dst.len(), false) {
+        return Err(Error::Fault);
+    }
+    copy(dst.as_mut_ptr(), src as *const u8, dst.len())
+}
+
+/// Copy `src` to the user address `dst`
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), Error> {
+    if !access_ok(dst, src.len(), true) {
+        return Err(Error::Fault);
+    }
+    copy(dst as *mut u8, src.as_ptr(), src.len())
+}
+
+/// Read a value from the user address `src`
+pub fn get_user<T: Pod>(src: usize) -> Result<T, Error> {
+    if !access_ok(src, size_of::<T>(), false) {
+        return Err(Error::Fault);
+    }
+    let mut value = MaybeUninit::<T>::uninit();
+    copy(
+        value.as_mut_ptr() as *mut u8,
+        src as *const u8,
+        size_of::<T>(),
+    )?;
+    Ok(unsafe { value.assume_init() })
+}
+
+/// Write a value to the user address `dst`
+pub fn put_user<T: Pod>(dst: usize, value: T) -> Result<(), Error> {
+    let bytes =
+        unsafe { core::slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>()) };
+    copy_to_user(dst, bytes)
+}
+
+/// Where `test` maps its user page, the page after it stays unmapped
+const TEST_ADDR: usize = USER_END - 2 * PAGE_SIZE;
+
+/// Check the accessors against good and bad user pointers, with
+/// `uaccess_test`
+///
+/// A user page is mapped for the test. Accesses to it have to work, those
+/// to kernel memory, unmapped pages and past the end of the user half have
+/// to fail with EFAULT. The last check faults inside `__copy_user`, past
+/// `access_ok`, which only the exception table recovers from.
+pub fn test() {
+    if !cmdline::has("uaccess_test") {
+        return;
+    }
+    if satp::read().bits() == 0 {
+        warn!("uaccess_test: needs paging, which is off with nokaslr");
+        return;
+    }
+
+    let page = page::zalloc(1);
+    if page.is_null() {
+        warn!("uaccess_test: no memory");
+        return;
+    }
+    unsafe { mem::map_user_page(TEST_ADDR, page as usize, Attribute::UserReadWrite) };
+
+    const MAGIC: u64 = 0x0123_4567_89ab_cdef;
+    let unmapped = TEST_ADDR + PAGE_SIZE;
+    let mut buf = [0u8; 8];
+    let checks = [
+        ("put_user", put_user(TEST_ADDR, MAGIC) == Ok(())),
+        ("get_user", get_user::<u64>(TEST_ADDR) == Ok(MAGIC)),
+        (
+            "kernel page",
+            get_user::<u64>(page as usize) == Err(Error::Fault),
+        ),
+        (
+            "unmapped page",
+            copy_to_user(unmapped, &buf) == Err(Error::Fault),
+        ),
+        (
+            "across the end of the page",
+            copy_from_user(&mut buf, unmapped - 4) == Err(Error::Fault),
+        ),
+        (
+            "past the user half",
+            get_user::<u8>(USER_END) == Err(Error::Fault),
+        ),
+        (
+            "fault in __copy_user",
+            copy(buf.as_mut_ptr(), (unmapped - 4) as *const u8, buf.len()) == Err(Error::Fault),
+        ),
+    ];
+
+    let mut passed = true;
+    for (name, ok) in checks {
+        if !ok {
+            warn!("uaccess_test: {} failed", name);
+            passed = false;
+        }
+    }
+    info!("uaccess_test: {}", if passed { "passed" } else { "FAILED" });
+
+    unsafe { mem::unmap_page(TEST_ADDR) };
+    page::dealloc(page);
+}
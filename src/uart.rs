@@ -1,20 +1,94 @@
+// NS16550A UART
+//
+// Output goes through a transmit ring once the interrupt is set up. The
+// THRE interrupt refills the FIFO from the ring, 16 bytes at a time, and is
+// only enabled while the ring has data. Before that, and whenever the ring
+// is full, writers poll LSR.THRE and fill the FIFO themselves, so output
+// also drains with interrupts disabled.
+//
+// Received bytes go to a receive ring for `read`, and to a second ring that
+// the echo tasklet prints from.
+
+use crate::arch::riscv::without_interrupts;
+use crate::console::Backend;
+use crate::fdt;
+use crate::interrupt::{self, IrqReturn};
+use crate::plic::InterruptId;
+use crate::print;
+use crate::println;
+use crate::softirq::{tasklet_schedule, Tasklet};
+
 use core::fmt::Error;
 use core::fmt::Write;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use log::{info, warn};
+use spin::Mutex;
 
 pub const UART_BASE_ADDR: usize = 0x10_000_000;
 
-#[macro_export]
-macro_rules! print {
-    ($($args:tt)+) => ({
-	use core::fmt::Write;
-	let _ = write!(crate::uart::Uart::new(crate::uart::UART_BASE_ADDR), $($args)+);
-    });
+/// Input clock of the UART of QEMU virt
+const DEFAULT_CLOCK: u32 = 3_686_400;
+const DEFAULT_BAUD: u32 = 115_200;
+
+/// Depth of the transmit and receive FIFOs
+const FIFO_SIZE: usize = 16;
+
+mod offset {
+    /// Receive buffer, transmit holding register, divisor latch low
+    pub const RBR_THR_DLL: usize = 0;
+    /// Interrupt enable, divisor latch high
+    pub const IER_DLM: usize = 1;
+    /// Interrupt identification on read, FIFO control on write
+    pub const IIR_FCR: usize = 2;
+    pub const LCR: usize = 3;
+    pub const MCR: usize = 4;
+    pub const LSR: usize = 5;
+    pub const MSR: usize = 6;
 }
-#[macro_export]
-macro_rules! println {
-    () => ({ print!("\r\n") });
-    ($fmt:expr) => ({ $crate::print!(concat!($fmt, "\r\n")) });
-    ($fmt:expr, $($args:tt)+) => ({ $crate::print!(concat!($fmt, "\r\n"), $($args)+) });
+
+const IER_RX: u8 = 1 << 0;
+const IER_THRE: u8 = 1 << 1;
+const IER_LINE_STATUS: u8 = 1 << 2;
+
+const IIR_NO_INTERRUPT: u8 = 1 << 0;
+const IIR_MODEM_STATUS: u8 = 0;
+const IIR_THRE: u8 = 1;
+const IIR_RX: u8 = 2;
+const IIR_LINE_STATUS: u8 = 3;
+const IIR_RX_TIMEOUT: u8 = 6;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+
+/// 8 data bits, no parity, 1 stop bit
+const LCR_8N1: u8 = 0b11;
+/// Divisor latch access
+const LCR_DLAB: u8 = 1 << 7;
+
+/// DTR, RTS, and OUT2 which gates the interrupt on PC style boards
+const MCR_DTR_RTS_OUT2: u8 = 0b1011;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+/// Bytes in the receive FIFO that raise the receive interrupt
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Trigger {
+    One = 0,
+    Four = 1,
+    Eight = 2,
+    Fourteen = 3,
+}
+
+impl Trigger {
+    const fn fcr(self) -> u8 {
+        (self as u8) << 6
+    }
 }
 
 pub struct Uart {
@@ -23,9 +97,7 @@ pub struct Uart {
 
 impl Write for Uart {
     fn write_str(&mut self, out: &str) -> Result<(), Error> {
-        for c in out.bytes() {
-            self.put(c);
-        }
+        write(out.as_bytes());
         Ok(())
     }
 }
@@ -35,95 +107,309 @@ impl Uart {
         Uart { base_address }
     }
 
+    fn read_reg(&self, offset: usize) -> u8 {
+        unsafe { ((self.base_address + offset) as *const u8).read_volatile() }
+    }
+
+    fn write_reg(&self, offset: usize, value: u8) {
+        unsafe { ((self.base_address + offset) as *mut u8).write_volatile(value) }
+    }
+
+    /// Set up 8N1 at the default speed, with the FIFOs on and no interrupts
     pub fn init(&mut self) {
-        let ptr = self.base_address as *mut u8;
-        unsafe {
-            // First, set the word length, which
-            // are bits 0 and 1 of the line control register (LCR)
-            // which is at base_address + 3
-            // We can easily write the value 3 here or 0b11, but I'm
-            // extending it so that it is clear we're setting two individual
-            // fields
-            //                         Word 0     Word 1
-            //                         ~~~~~~     ~~~~~~
-            let lcr: u8 = (1 << 0) | (1 << 1);
-            ptr.add(3).write_volatile(lcr);
-
-            // Now, enable the FIFO, which is bit index 0 of the FIFO
-            // control register (FCR at offset 2).
-            // Again, we can just write 1 here, but when we use left shift,
-            // it's easier to see that we're trying to write bit index #0.
-            ptr.add(2).write_volatile(1 << 0);
-
-            // Enable receiver buffer interrupts, which is at bit index
-            // 0 of the interrupt enable register (IER at offset 1).
-            ptr.add(1).write_volatile(1 << 0);
-
-            // If we cared about the divisor, the code below would set the divisor
-            // from a global clock rate of 22.729 MHz (22,729,000 cycles per second)
-            // to a signaling rate of 2400 (BAUD). We usually have much faster signalling
-            // rates nowadays, but this demonstrates what the divisor actually does.
-            // The formula given in the NS16500A specification for calculating the divisor
-            // is:
-            // divisor = ceil( (clock_hz) / (baud_sps x 16) )
-            // So, we substitute our values and get:
-            // divisor = ceil( 22_729_000 / (2400 x 16) )
-            // divisor = ceil( 22_729_000 / 38_400 )
-            // divisor = ceil( 591.901 ) = 592
-
-            // The divisor register is two bytes (16 bits), so we need to split the value
-            // 592 into two bytes. Typically, we would calculate this based on measuring
-            // the clock rate, but again, for our purposes [qemu], this doesn't really do
-            // anything.
-            let divisor: u16 = 592;
-            let divisor_least: u8 = (divisor & 0xff).try_into().unwrap();
-            let divisor_most: u8 = (divisor >> 8).try_into().unwrap();
-
-            // Notice that the divisor register DLL (divisor latch least) and DLM (divisor latch most)
-            // have the same base address as the receiver/transmitter and the interrupt enable register.
-            // To change what the base address points to, we open the "divisor latch" by writing 1 into
-            // the Divisor Latch Access Bit (DLAB), which is bit index 7 of the Line Control Register (LCR)
-            // which is at base_address + 3.
-            ptr.add(3).write_volatile(lcr | 1 << 7);
-
-            // Now, base addresses 0 and 1 point to DLL and DLM, respectively.
-            // Put the lower 8 bits of the divisor into DLL
-            ptr.add(0).write_volatile(divisor_least);
-            ptr.add(1).write_volatile(divisor_most);
-
-            // Now that we've written the divisor, we never have to touch this again. In hardware, this
-            // will divide the global clock (22.729 MHz) into one suitable for 2,400 signals per second.
-            // So, to once again get access to the RBR/THR/IER registers, we need to close the DLAB bit
-            // by clearing it to 0. Here, we just restore the original value of lcr.
-            ptr.add(3).write_volatile(lcr);
+        self.write_reg(offset::IER_DLM, 0);
+        self.write_reg(offset::LCR, LCR_8N1);
+        self.set_baud(DEFAULT_CLOCK, DEFAULT_BAUD);
+        self.set_fifo(Trigger::Eight);
+        self.write_reg(offset::MCR, MCR_DTR_RTS_OUT2);
+    }
+
+    /// Set the speed to `baud` from an input clock of `clock` Hz
+    ///
+    /// Returns the divisor, the closest to `clock / (16 * baud)`. Waits for
+    /// the transmitter to go idle first, so no byte goes out half and half.
+    pub fn set_baud(&mut self, clock: u32, baud: u32) -> u16 {
+        while self.read_reg(offset::LSR) & LSR_TEMT == 0 {
+            spin_loop();
         }
+        let divisor = (clock + 8 * baud) / (16 * baud.max(1));
+        let divisor = divisor.clamp(1, u16::MAX as u32) as u16;
+
+        let lcr = self.read_reg(offset::LCR);
+        self.write_reg(offset::LCR, lcr | LCR_DLAB);
+        self.write_reg(offset::RBR_THR_DLL, divisor as u8);
+        self.write_reg(offset::IER_DLM, (divisor >> 8) as u8);
+        self.write_reg(offset::LCR, lcr & !LCR_DLAB);
+        divisor
+    }
+
+    /// Enable and clear the FIFOs, raising the receive interrupt at `trigger`
+    pub fn set_fifo(&mut self, trigger: Trigger) {
+        self.write_reg(
+            offset::IIR_FCR,
+            FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | trigger.fcr(),
+        );
     }
 
+    fn set_interrupts(&mut self, ier: u8) {
+        self.write_reg(offset::IER_DLM, ier);
+    }
+
+    fn enable_thre_interrupt(&mut self, enable: bool) {
+        let ier = self.read_reg(offset::IER_DLM);
+        let new = if enable {
+            ier | IER_THRE
+        } else {
+            ier & !IER_THRE
+        };
+        if new != ier {
+            self.write_reg(offset::IER_DLM, new);
+        }
+    }
+
+    /// Check if the transmit FIFO is empty
+    fn thre(&self) -> bool {
+        self.read_reg(offset::LSR) & LSR_THRE != 0
+    }
+
+    /// Write a byte, waiting for room in the transmit FIFO
     pub fn put(&mut self, c: u8) {
-        let ptr = self.base_address as *mut u8;
-        unsafe {
-            ptr.add(0).write_volatile(c);
+        while !self.thre() {
+            spin_loop();
         }
+        self.write_reg(offset::RBR_THR_DLL, c);
     }
 
+    /// Read a received byte, counting overruns
     pub fn get(&mut self) -> Option<u8> {
-        let ptr = self.base_address as *mut u8;
-        unsafe {
-            if ptr.add(5).read_volatile() & 1 == 0 {
-                // The DR bit is 0, meaning no data
-                None
-            } else {
-                // The DR bit is 1, meaning data!
-                Some(ptr.add(0).read_volatile())
+        let lsr = self.read_reg(offset::LSR);
+        if lsr & LSR_OVERRUN != 0 {
+            OVERRUNS.fetch_add(1, Ordering::Relaxed);
+        }
+        if lsr & LSR_DATA_READY == 0 {
+            None
+        } else {
+            Some(self.read_reg(offset::RBR_THR_DLL))
+        }
+    }
+}
+
+/// A byte ring buffer
+struct Ring<const N: usize> {
+    data: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> Ring<N> {
+    const fn new() -> Self {
+        Self {
+            data: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Add a byte, returns false if the ring is full
+    fn push(&mut self, c: u8) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.data[(self.head + self.len) % N] = c;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let c = self.data[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(c)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+const TX_BUFFER_SIZE: usize = 1024;
+const RX_BUFFER_SIZE: usize = 256;
+const ECHO_BUFFER_SIZE: usize = 64;
+
+/// Rings are only locked with interrupts off, also from the interrupt
+/// handler, which runs with them on so that other PLIC sources can nest
+static TX: Mutex<Ring<TX_BUFFER_SIZE>> = Mutex::new(Ring::new());
+static RX: Mutex<Ring<RX_BUFFER_SIZE>> = Mutex::new(Ring::new());
+static ECHO: Mutex<Ring<ECHO_BUFFER_SIZE>> = Mutex::new(Ring::new());
+
+/// Set once output goes through the transmit ring
+static BUFFERED: AtomicBool = AtomicBool::new(false);
+
+/// Bytes lost because the receive FIFO overflowed
+static OVERRUNS: AtomicUsize = AtomicUsize::new(0);
+/// Bytes lost because the receive ring was full
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+static RX_TASKLET: Tasklet = Tasklet::new(uart_rx, 0);
+
+fn uart() -> Uart {
+    Uart::new(UART_BASE_ADDR)
+}
+
+/// Bytes lost because the receive FIFO overflowed
+pub fn overruns() -> usize {
+    OVERRUNS.load(Ordering::Relaxed)
+}
+
+/// Bytes lost because nobody read them in time
+pub fn dropped() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Move bytes from the ring to the transmit FIFO, if it is empty
+///
+/// The THRE interrupt is left enabled while there is more to send.
+fn transmit(uart: &mut Uart, tx: &mut Ring<TX_BUFFER_SIZE>) {
+    if uart.thre() {
+        for _ in 0..FIFO_SIZE {
+            match tx.pop() {
+                Some(c) => uart.write_reg(offset::RBR_THR_DLL, c),
+                None => break,
             }
         }
     }
+    uart.enable_thre_interrupt(!tx.is_empty());
 }
 
-pub fn uart_interrupt() {
-    let mut uart = Uart::new(UART_BASE_ADDR);
-    if let Some(c) = uart.get() {
-        drop(uart);
+/// Move the received bytes to the rings
+fn receive(uart: &mut Uart) {
+    let received = without_interrupts(|| {
+        let mut rx = RX.lock();
+        let mut echo = ECHO.lock();
+        let mut received = false;
+        while let Some(c) = uart.get() {
+            if !rx.push(c) {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+            echo.push(c);
+            received = true;
+        }
+        received
+    });
+
+    if received {
+        tasklet_schedule(&RX_TASKLET);
+    }
+}
+
+/// Write `bytes`, returns once all of them are queued
+///
+/// Waits for room in the ring, safe with interrupts disabled.
+pub fn write(bytes: &[u8]) {
+    let mut uart = uart();
+    if !BUFFERED.load(Ordering::Acquire) {
+        for &c in bytes {
+            uart.put(c);
+        }
+        return;
+    }
+
+    let mut bytes = bytes.iter().peekable();
+    while bytes.peek().is_some() {
+        without_interrupts(|| {
+            let mut tx = TX.lock();
+            while let Some(&&c) = bytes.peek() {
+                if !tx.push(c) {
+                    break;
+                }
+                bytes.next();
+            }
+            // The interrupt may go to another hart, or not come at all
+            // with interrupts off, so make progress here too
+            transmit(&mut uart, &mut tx);
+        });
+        if bytes.peek().is_some() {
+            spin_loop();
+        }
+    }
+}
+
+/// Wait until everything queued has been sent
+pub fn flush() {
+    let mut uart = uart();
+    loop {
+        let empty = without_interrupts(|| {
+            let mut tx = TX.lock();
+            transmit(&mut uart, &mut tx);
+            tx.is_empty()
+        });
+        if empty {
+            break;
+        }
+        spin_loop();
+    }
+}
+
+/// Read received bytes into `buf`, waiting for at least one
+///
+/// Returns the number of bytes read.
+pub fn read(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+
+    let mut uart = uart();
+    loop {
+        let read = without_interrupts(|| {
+            let mut rx = RX.lock();
+            let mut read = 0;
+            while read < buf.len() {
+                match rx.pop() {
+                    Some(c) => buf[read] = c,
+                    None => break,
+                }
+                read += 1;
+            }
+            read
+        });
+        if read > 0 {
+            return read;
+        }
+        // The interrupt may go to another hart
+        receive(&mut uart);
+        spin_loop();
+    }
+}
+
+/// The UART as a console backend
+pub struct UartConsole;
+
+pub static CONSOLE: UartConsole = UartConsole;
+
+impl Backend for UartConsole {
+    fn name(&self) -> &'static str {
+        "uart"
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        write(bytes);
+    }
+
+    fn flush(&self) {
+        flush();
+    }
+
+    unsafe fn force_unlock(&self) {
+        TX.force_unlock();
+    }
+}
+
+/// Echo the received bytes, outside of the interrupt handler
+fn uart_rx(_: usize) {
+    while let Some(c) = without_interrupts(|| ECHO.lock().pop()) {
         match c {
             8 => print!("{} {}", 8 as char, 8 as char),
             10 | 13 => println!(),
@@ -131,3 +417,65 @@ pub fn uart_interrupt() {
         }
     }
 }
+
+pub fn uart_interrupt(_: InterruptId, _: usize) -> IrqReturn {
+    let mut uart = uart();
+    let mut handled = IrqReturn::None;
+    loop {
+        let iir = uart.read_reg(offset::IIR_FCR);
+        if iir & IIR_NO_INTERRUPT != 0 {
+            break;
+        }
+        handled = IrqReturn::Handled;
+
+        match (iir >> 1) & 0b111 {
+            // Reading LSR clears it
+            IIR_LINE_STATUS => {
+                if uart.read_reg(offset::LSR) & LSR_OVERRUN != 0 {
+                    OVERRUNS.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            IIR_RX | IIR_RX_TIMEOUT => receive(&mut uart),
+            IIR_THRE => without_interrupts(|| transmit(&mut uart, &mut TX.lock())),
+            IIR_MODEM_STATUS => {
+                uart.read_reg(offset::MSR);
+            }
+            _ => break,
+        }
+    }
+    handled
+}
+
+/// Find the UART in the device tree, returns its clock and speed
+fn probe() -> (u32, u32) {
+    let node = fdt::fdt().and_then(|fdt| fdt.find_node("/soc/serial"));
+    let clock = node
+        .as_ref()
+        .and_then(|node| node.property("clock-frequency"))
+        .and_then(|clock| clock.as_u32())
+        .unwrap_or(DEFAULT_CLOCK);
+    let baud = node
+        .as_ref()
+        .and_then(|node| node.property("current-speed"))
+        .and_then(|baud| baud.as_u32())
+        .unwrap_or(DEFAULT_BAUD);
+    (clock, baud)
+}
+
+/// Set the speed from the device tree and switch to interrupt driven I/O
+pub fn init() {
+    let (clock, baud) = probe();
+    let mut uart = uart();
+    let divisor = uart.set_baud(clock, baud);
+    info!(
+        "UART: {} baud from a {} Hz clock, divisor {}",
+        baud, clock, divisor
+    );
+
+    if let Err(err) = interrupt::request_irq(InterruptId::Uart0, uart_interrupt, 0) {
+        warn!("UART: no interrupt, {:?}", err);
+        return;
+    }
+    uart.set_interrupts(IER_RX | IER_LINE_STATUS);
+    BUFFERED.store(true, Ordering::Release);
+}
@@ -0,0 +1,282 @@
+// Soft lockup and hung hart detection
+//
+// Each hart runs a watchdog timer that records a heartbeat and pings the
+// other harts with an IPI, and its idle loop records that it was scheduled.
+// A hart whose timer or idle loop has not run within the window is locked
+// up: softly if it still answers the pings, so it spins with interrupts
+// enabled, else hard, with interrupts disabled.
+//
+// Either way it is asked for its registers through its msip. Machine
+// interrupts are taken whatever sstatus.SIE says, so `timervec` saves them
+// even on a hart that spins with interrupts off, like an NMI would.
+
+use crate::arch::riscv::thread_pointer;
+use crate::backtrace;
+use crate::clint;
+use crate::clocksource::{self, Instant};
+use crate::cmdline;
+use crate::hart::{self, MAX_HARTS};
+use crate::ipi::{self, Ipi};
+use crate::print;
+use crate::println;
+use crate::timer::{self, Timer};
+use crate::trap::{reg, TrapFrame};
+
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use log::{error, info, warn};
+use spin::Mutex;
+
+/// Window in milliseconds, unless set with `watchdog=<seconds>`
+const DEFAULT_WINDOW_MS: u64 = 10_000;
+/// Checks per window
+const SAMPLES: u32 = 4;
+/// How long a hart gets to save its registers
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Registers of a hart, saved by `timervec` on request
+#[repr(C)]
+struct Snapshot {
+    /// Set to ask for the registers, cleared by `timervec`
+    request: AtomicUsize,
+    /// Set by `timervec` once `frame` is written
+    done: AtomicUsize,
+    /// `sepc` is where the hart was interrupted, the other CSRs are as the
+    /// hart left them
+    frame: TrapFrame,
+}
+
+const SNAPSHOT: Snapshot = Snapshot {
+    request: AtomicUsize::new(0),
+    done: AtomicUsize::new(0),
+    frame: TrapFrame {
+        regs: [0; 32],
+        sepc: 0,
+        sstatus: 0,
+        stval: 0,
+        scause: 0,
+    },
+};
+
+/// Written by `timervec` in machine mode
+static mut SNAPSHOTS: [Snapshot; MAX_HARTS] = [SNAPSHOT; MAX_HARTS];
+
+/// Window in milliseconds, 0 when the watchdog is off
+static WINDOW_MS: AtomicU64 = AtomicU64::new(DEFAULT_WINDOW_MS);
+/// Panic on a lockup, else only report it
+static PANIC: AtomicBool = AtomicBool::new(false);
+
+const NEVER: AtomicU64 = AtomicU64::new(0);
+const NO: AtomicBool = AtomicBool::new(false);
+
+/// Harts whose watchdog timer runs
+static WATCHED: [AtomicBool; MAX_HARTS] = [NO; MAX_HARTS];
+/// When the idle loop of each hart last ran, in mtime ticks
+static SCHEDULED: [AtomicU64; MAX_HARTS] = [NEVER; MAX_HARTS];
+/// When the watchdog timer of each hart last ran
+static TICKED: [AtomicU64; MAX_HARTS] = [NEVER; MAX_HARTS];
+/// When each hart last answered a ping
+static PINGED: [AtomicU64; MAX_HARTS] = [NEVER; MAX_HARTS];
+/// Harts reported locked up, until they recover
+static REPORTED: [AtomicBool; MAX_HARTS] = [NO; MAX_HARTS];
+
+const WATCHDOG_TIMER: Timer = Timer::new(check, 0);
+
+static TIMERS: [Timer; MAX_HARTS] = [WATCHDOG_TIMER; MAX_HARTS];
+
+/// Serializes dumps, so that their output does not mix
+static DUMP: Mutex<()> = Mutex::new(());
+
+fn snapshot(hart: usize) -> &'static Snapshot {
+    unsafe { &*ptr::addr_of!(SNAPSHOTS[hart]) }
+}
+
+/// Address of the snapshot of `hart`, for `timervec`
+pub(crate) fn snapshot_addr(hart: usize) -> usize {
+    unsafe { ptr::addr_of!(SNAPSHOTS[hart]) as usize }
+}
+
+/// The window, None when the watchdog is off
+pub fn window() -> Option<Duration> {
+    match WINDOW_MS.load(Ordering::Relaxed) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+/// Change the window of a running watchdog, it can not be turned off
+pub fn set_window(window: Duration) {
+    let ms = (window.as_millis() as u64).max(1);
+    if WINDOW_MS.load(Ordering::Relaxed) != 0 {
+        WINDOW_MS.store(ms, Ordering::Relaxed);
+    }
+}
+
+/// Panic on a lockup, else only report it
+pub fn set_panic(panic: bool) {
+    PANIC.store(panic, Ordering::Relaxed);
+}
+
+fn load(beat: &AtomicU64) -> Instant {
+    Instant::from_ticks(beat.load(Ordering::Relaxed))
+}
+
+fn store(beat: &AtomicU64, now: Instant) {
+    beat.store(now.ticks(), Ordering::Relaxed);
+}
+
+/// Record that the current hart was scheduled
+///
+/// Called by the idle loop. Code that keeps a hart busy for longer than the
+/// window on purpose calls it as well.
+pub fn touch() {
+    store(&SCHEDULED[thread_pointer()], Instant::now());
+}
+
+/// Handle a `Watchdog` IPI, the ping of another hart
+pub(crate) fn ping_interrupt() {
+    store(&PINGED[thread_pointer()], Instant::now());
+}
+
+fn start_timer(hart: usize, window: Duration) {
+    let period = clocksource::duration_to_ticks(window / SAMPLES);
+    if timer::add_timer(&TIMERS[hart], period as usize).is_err() {
+        warn!("watchdog: no timer left on hart {}", hart);
+    }
+}
+
+/// The watchdog timer, checks the other harts
+fn check(_: usize) {
+    let hart = thread_pointer();
+    let now = Instant::now();
+    store(&TICKED[hart], now);
+
+    let window = match window() {
+        Some(window) => window,
+        None => return,
+    };
+
+    let mut others = hart::online_mask();
+    others.clear(hart);
+    ipi::send_mask(others, Ipi::Watchdog);
+    for other in others
+        .iter()
+        .filter(|&other| WATCHED[other].load(Ordering::Acquire))
+    {
+        check_hart(other, now, window);
+    }
+
+    start_timer(hart, window);
+}
+
+fn check_hart(hart: usize, now: Instant, window: Duration) {
+    let last = load(&SCHEDULED[hart]).min(load(&TICKED[hart]));
+    let stuck = now - last;
+    if stuck <= window {
+        REPORTED[hart].store(false, Ordering::Relaxed);
+        return;
+    }
+    // Once per lockup, and only by one hart
+    if REPORTED[hart].swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    if now - load(&PINGED[hart]) <= window {
+        error!(
+            "watchdog: soft lockup, hart {} stuck for {} ms",
+            hart,
+            stuck.as_millis()
+        );
+    } else {
+        error!(
+            "watchdog: hard lockup, hart {} stuck with interrupts off for {} ms",
+            hart,
+            stuck.as_millis()
+        );
+    }
+    dump_hart(hart);
+
+    if PANIC.load(Ordering::Relaxed) {
+        panic!("watchdog: hart {} locked up", hart);
+    }
+}
+
+/// Print the registers and backtrace of `hart`, wherever it is
+///
+/// Works with interrupts disabled on `hart`. The backtrace is only reliable
+/// if `hart` stays where it is. Returns false if `hart` did not answer.
+pub fn dump_hart(hart: usize) -> bool {
+    let _guard = DUMP.lock();
+    let snapshot = snapshot(hart);
+    snapshot.done.store(0, Ordering::Relaxed);
+    snapshot.request.store(1, Ordering::Release);
+    clint::send_soft(hart);
+
+    let deadline = Instant::now() + SNAPSHOT_TIMEOUT;
+    while snapshot.done.load(Ordering::Acquire) == 0 {
+        if Instant::now() >= deadline {
+            snapshot.request.store(0, Ordering::Relaxed);
+            warn!("watchdog: hart {} did not save its registers", hart);
+            return false;
+        }
+        spin_loop();
+    }
+
+    let frame = &snapshot.frame;
+    println!("hart {} at 0x{:016x}", hart, frame.sepc);
+    print!("{}", frame);
+    backtrace::backtrace_of(hart, frame.sepc, frame.reg(reg::FP));
+    true
+}
+
+/// Read the window from the command line
+///
+/// `watchdog=<seconds>` sets it, `watchdog=0` turns the watchdog off, and
+/// with `watchdog_panic` a lockup panics.
+pub fn init() {
+    if let Some(value) = cmdline::get("watchdog") {
+        match value.parse::<u64>() {
+            Ok(secs) => WINDOW_MS.store(secs.saturating_mul(1000), Ordering::Relaxed),
+            Err(_) => warn!("watchdog: invalid window {}", value),
+        }
+    }
+    set_panic(cmdline::has("watchdog_panic"));
+
+    match window() {
+        Some(window) => info!(
+            "watchdog: {} ms window{}",
+            window.as_millis(),
+            if PANIC.load(Ordering::Relaxed) {
+                ", panics on lockup"
+            } else {
+                ""
+            }
+        ),
+        None => info!("watchdog: off"),
+    }
+}
+
+/// Start watching the current hart
+pub fn hartinit() {
+    let hart = thread_pointer();
+    let now = Instant::now();
+    store(&SCHEDULED[hart], now);
+    store(&TICKED[hart], now);
+    store(&PINGED[hart], now);
+    REPORTED[hart].store(false, Ordering::Relaxed);
+
+    if let Some(window) = window() {
+        WATCHED[hart].store(true, Ordering::Release);
+        start_timer(hart, window);
+    }
+}
+
+/// Stop watching the current hart, before it is stopped
+pub fn stop() {
+    let hart = thread_pointer();
+    WATCHED[hart].store(false, Ordering::Release);
+    timer::del_timer(&TIMERS[hart]);
+}
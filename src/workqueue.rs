@@ -0,0 +1,177 @@
+// Workqueues, deferred work run in thread context
+//
+// There is no scheduler, so the idle loop of each hart, `worker`, is its
+// worker thread. Queued work runs there with interrupts enabled, on
+// whichever hart picks it up first, and may take as long as it needs.
+
+use crate::arch::riscv::{self, without_interrupts};
+use crate::softirq;
+use crate::tick;
+use crate::watchdog;
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use log::warn;
+use spin::Mutex;
+
+/// Number of workqueues that can be registered, including `SYSTEM`
+const MAX_QUEUES: usize = 8;
+
+/// Deferred work
+pub struct Work {
+    func: fn(usize),
+    data: usize,
+    pending: AtomicBool,
+    next: AtomicPtr<Work>,
+}
+
+impl Work {
+    pub const fn new(func: fn(usize), data: usize) -> Self {
+        Self {
+            func,
+            data,
+            pending: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Relaxed)
+    }
+}
+
+/// Linked list of queued work, oldest first
+struct Queue {
+    head: *mut Work,
+    tail: *mut Work,
+}
+
+// Only points to `&'static Work`
+unsafe impl Send for Queue {}
+
+pub struct WorkQueue {
+    name: &'static str,
+    queue: Mutex<Queue>,
+}
+
+impl WorkQueue {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            queue: Mutex::new(Queue {
+                head: ptr::null_mut(),
+                tail: ptr::null_mut(),
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Queue `work`, returns false if it already was queued
+    ///
+    /// Can be called from interrupt context.
+    pub fn queue(&self, work: &'static Work) -> bool {
+        if work.pending.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+
+        let work = work as *const Work as *mut Work;
+        without_interrupts(|| {
+            let mut queue = self.queue.lock();
+            match unsafe { queue.tail.as_ref() } {
+                Some(tail) => tail.next.store(work, Ordering::Relaxed),
+                None => queue.head = work,
+            }
+            queue.tail = work;
+        });
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        without_interrupts(|| self.queue.lock().head.is_null())
+    }
+
+    fn pop(&self) -> Option<&'static Work> {
+        without_interrupts(|| {
+            let mut queue = self.queue.lock();
+            let work = unsafe { queue.head.as_ref()? };
+            queue.head = work.next.swap(ptr::null_mut(), Ordering::Relaxed);
+            if queue.head.is_null() {
+                queue.tail = ptr::null_mut();
+            }
+            Some(work)
+        })
+    }
+
+    /// Run the queued work, returns how many items ran
+    pub fn run(&self) -> usize {
+        let mut count = 0;
+        while let Some(work) = self.pop() {
+            // Cleared first so that the work can queue itself again
+            work.pending.store(false, Ordering::Release);
+            (work.func)(work.data);
+            count += 1;
+        }
+        count
+    }
+}
+
+/// The default workqueue
+pub static SYSTEM: WorkQueue = WorkQueue::new("events");
+
+static QUEUES: Mutex<[Option<&'static WorkQueue>; MAX_QUEUES]> = Mutex::new({
+    let mut queues = [None; MAX_QUEUES];
+    queues[0] = Some(&SYSTEM);
+    queues
+});
+
+/// Let the workers run a workqueue
+pub fn register(wq: &'static WorkQueue) {
+    let mut queues = QUEUES.lock();
+    match queues.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(wq),
+        None => warn!("workqueue {}: too many workqueues", wq.name()),
+    }
+}
+
+/// Queue `work` on the default workqueue
+pub fn schedule_work(work: &'static Work) -> bool {
+    SYSTEM.queue(work)
+}
+
+fn queues() -> [Option<&'static WorkQueue>; MAX_QUEUES] {
+    *QUEUES.lock()
+}
+
+/// Whether there is work or softirqs left on the current hart
+fn pending() -> bool {
+    softirq::pending() || queues().iter().flatten().any(|wq| !wq.is_empty())
+}
+
+/// Run queued work forever
+///
+/// The idle loop of a hart. Sleeps in `wfi` when there is no work left.
+pub fn worker() -> ! {
+    loop {
+        watchdog::touch();
+        for wq in queues().iter().flatten() {
+            wq.run();
+        }
+
+        // Softirqs raised outside an interrupt, or left over by the last
+        // interrupt exit, run here. With interrupts off no work can be
+        // queued between the check and `wfi`, which still wakes up on a
+        // pending interrupt. The tick is stopped while asleep.
+        without_interrupts(|| {
+            softirq::do_softirq();
+            if !pending() {
+                tick::idle_enter();
+                riscv::wait();
+                tick::idle_exit();
+            }
+        });
+    }
+}